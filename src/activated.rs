@@ -0,0 +1,74 @@
+use std::{fs, io};
+use std::collections::BTreeMap;
+use std::env::VarError;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::xdg;
+
+#[derive(Error, Debug)]
+pub(crate) enum ActivatedError {
+    #[error("could not get $HOME environment variable")]
+    NoHomeEnvironmentVariable(#[source] VarError),
+    #[error("could not read {0}")]
+    CouldNotRead(PathBuf, #[source] io::Error),
+    #[error("could not parse {0}")]
+    CouldNotParse(PathBuf, #[source] serde_json::Error),
+    #[error("could not write {0}")]
+    CouldNotWrite(PathBuf, #[source] io::Error),
+}
+
+/// The content of each module file as of the last successful home-manager switch,
+/// kept in $XDG_STATE_HOME/hdn/activated.json so that hdn switch can roll back to it
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Activated {
+    files: BTreeMap<PathBuf, String>,
+}
+
+fn state_file() -> Result<PathBuf, ActivatedError> {
+    Ok(xdg::state_home()
+        .map_err(ActivatedError::NoHomeEnvironmentVariable)?
+        .join("hdn/activated.json"))
+}
+
+impl Activated {
+    pub(crate) fn load() -> Result<Activated, ActivatedError> {
+        use crate::activated::ActivatedError::*;
+
+        let path = state_file()?;
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Activated::default()),
+            Err(error) => return Err(CouldNotRead(path, error))
+        };
+        serde_json::from_str(&content).map_err(|error| CouldNotParse(path, error))
+    }
+
+    pub(crate) fn save(&self) -> Result<(), ActivatedError> {
+        use crate::activated::ActivatedError::*;
+
+        let path = state_file()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|error| CouldNotWrite(path.clone(), error))?;
+        }
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|error| CouldNotParse(path.clone(), error))?;
+        fs::write(&path, content).map_err(|error| CouldNotWrite(path, error))
+    }
+
+    pub(crate) fn get(&self, file: &Path) -> Option<&String> {
+        self.files.get(file)
+    }
+
+    pub(crate) fn insert(&mut self, file: &Path, content: String) {
+        self.files.insert(file.to_path_buf(), content);
+    }
+
+    /// Records `content` for `file` unless something was already recorded for it.
+    /// This is used before an edit that isn't activated right away,
+    /// assuming the file as it was is what's active.
+    pub(crate) fn insert_if_absent(&mut self, file: &Path, content: &str) {
+        self.files.entry(file.to_path_buf()).or_insert_with(|| content.to_string());
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Writes the file without ever leaving it half-written:
+/// the content goes to a temporary file in the same directory, which is synced and renamed over it.
+/// If the file is a symlink, the file it points to is replaced, so the symlink stays.
+/// The mode of the file is kept, and so is its owner when that's allowed.
+/// Only if the directory isn't writable, so no temporary file can be made there, is the file written in place.
+pub(crate) fn write(path: &Path, content: &str) -> io::Result<()> {
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(error) => return Err(error)
+    };
+    let metadata = match fs::metadata(&target) {
+        Ok(metadata) => Some(metadata),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error)
+    };
+
+    let temp = temp_path(&target);
+    let file = match OpenOptions::new().write(true).create_new(true).open(&temp) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::PermissionDenied && metadata.is_some() => {
+            return fs::write(&target, content);
+        }
+        Err(error) => return Err(error)
+    };
+    let result = write_temp(file, &temp, content, metadata.as_ref())
+        .and_then(|()| fs::rename(&temp, &target));
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(error);
+    }
+
+    // Sync the directory too, so the rename itself survives a crash
+    if let Some(dir) = target.parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Names the temporary file after the file, in the same directory so that renaming it is atomic.
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{name}.hdn-{}.tmp", process::id()))
+}
+
+fn write_temp(mut file: File, temp: &Path, content: &str, metadata: Option<&fs::Metadata>) -> io::Result<()> {
+    if let Some(metadata) = metadata {
+        // Giving the file to someone else takes privileges, so without them it's ours, with the same mode
+        let _ = chown(temp, Some(metadata.uid()), Some(metadata.gid()));
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(content.as_bytes())?;
+    file.sync_all()
+}
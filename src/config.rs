@@ -0,0 +1,65 @@
+use std::{fs, io};
+use std::collections::BTreeMap;
+use std::env::VarError;
+use std::path::PathBuf;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::xdg;
+
+#[derive(Error, Debug)]
+pub(crate) enum ConfigError {
+    #[error("could not get $HOME environment variable")]
+    NoHomeEnvironmentVariable(#[source] VarError),
+    #[error("could not read {0}")]
+    CouldNotRead(PathBuf, #[source] io::Error),
+    #[error("could not parse {0}")]
+    CouldNotParse(PathBuf, #[source] toml::de::Error),
+}
+
+/// hdn's own settings, read from $XDG_CONFIG_HOME/hdn/config.toml
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    /// Names for attribute paths that can be passed to --attr, e.g. `plugins = "programs.neovim.plugins"`
+    pub(crate) presets: BTreeMap<String, String>,
+    /// A Home Manager options.json to check program names against, instead of building one
+    pub(crate) options_json: Option<PathBuf>,
+    /// The command that builds Home Manager's documentation and prints its store path
+    pub(crate) options_command: Option<Vec<String>>,
+    /// Don't run home-manager switch after editing, as if --no-switch were always passed
+    pub(crate) no_switch: bool,
+    /// Insert packages at their sorted position when the list is sorted, as if --sort were always passed
+    pub(crate) sort: bool,
+    /// The width past which a list written on one line is split into one element per line
+    pub(crate) line_width: Option<usize>,
+    /// The command that checks whether a package exists in nixpkgs; see nixpkgs::Evaluator
+    pub(crate) evaluator: Option<Vec<String>>,
+    /// The command that prints nixpkgs as `nix-env -qaP --json --meta` does, for hdn search
+    pub(crate) index_command: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Expands a preset name passed to --attr; anything else is taken as an attribute path.
+    pub(crate) fn resolve_attr(&self, attr: &str) -> String {
+        self.presets.get(attr)
+            .cloned()
+            .unwrap_or_else(|| attr.to_string())
+    }
+}
+
+pub(crate) fn load() -> Result<Config, ConfigError> {
+    use crate::config::ConfigError::*;
+
+    let path = xdg::config_home()
+        .map_err(NoHomeEnvironmentVariable)?
+        .join("hdn/config.toml");
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(error) => return Err(CouldNotRead(path, error))
+    };
+
+    toml::from_str(&content).map_err(|error| CouldNotParse(path, error))
+}
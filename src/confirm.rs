@@ -0,0 +1,111 @@
+use std::{env, fs, io, process};
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::process::Command;
+use thiserror::Error;
+
+use crate::diff;
+
+#[derive(Error, Debug)]
+pub(crate) enum ConfirmError {
+    #[error("could not read the answer")]
+    CouldNotReadAnswer(#[source] io::Error),
+    #[error("could not write the proposed changes to a temporary file")]
+    CouldNotWriteTempFile(#[source] io::Error),
+    #[error("could not read the edited changes back from the temporary file")]
+    CouldNotReadTempFile(#[source] io::Error),
+    #[error("could not run the editor {0}")]
+    CouldNotRunEditor(String, #[source] io::Error),
+    #[error("the editor {0} returned a non-zero exit code")]
+    EditorUnsuccessful(String),
+}
+
+/// Asks whether to apply the changes from `content` to `new_content`, whose diff has just been printed.
+///
+/// Answering `e` opens $EDITOR on the proposed content, then prints the new diff and asks again.
+/// Returns the content to write, or `None` if the user declined.
+pub(crate) fn confirm(content: &String, new_content: String) -> Result<Option<String>, ConfirmError> {
+    use crate::confirm::ConfirmError::*;
+
+    let mut new_content = new_content;
+    loop {
+        print!("Apply these changes? [Y/n/e] ");
+        io::stdout().flush().map_err(CouldNotReadAnswer)?;
+
+        let mut answer = String::new();
+        let read = io::stdin().read_line(&mut answer).map_err(CouldNotReadAnswer)?;
+        if read == 0 {
+            println!();
+            return Ok(None);
+        }
+
+        match answer.trim().to_lowercase().as_str() {
+            "" | "y" | "yes" => {
+                println!();
+                return Ok(Some(new_content));
+            }
+            "n" | "no" => {
+                println!();
+                return Ok(None);
+            }
+            "e" | "edit" => {
+                new_content = edit(&new_content)?;
+                println!();
+                diff::print_diff(content, &new_content);
+                println!();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn edit(content: &str) -> Result<String, ConfirmError> {
+    use crate::confirm::ConfirmError::*;
+
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+
+    let dir = temp_dir().map_err(CouldNotWriteTempFile)?;
+    let path = dir.join("home.nix");
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(content.as_bytes()));
+    if let Err(error) = written {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(CouldNotWriteTempFile(error));
+    }
+
+    // $EDITOR may include arguments, like "code --wait"
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let status = Command::new(program)
+        .args(words)
+        .arg(&path)
+        .status();
+
+    let result = match status {
+        Ok(status) if status.success() => fs::read_to_string(&path).map_err(CouldNotReadTempFile),
+        Ok(_) => Err(EditorUnsuccessful(editor)),
+        Err(error) => Err(CouldNotRunEditor(editor, error)),
+    };
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Creates a directory for the file to edit that only the user can get into,
+/// so that nobody else can swap the file for a symlink or read it.
+fn temp_dir() -> io::Result<PathBuf> {
+    let mut attempt = 0;
+    loop {
+        let dir = env::temp_dir().join(format!("hdn-{}-{attempt}", process::id()));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(error) => return Err(error),
+        }
+    }
+}
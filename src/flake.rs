@@ -0,0 +1,184 @@
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use indexmap::IndexMap;
+use rnix::{SyntaxKind, SyntaxNode};
+use thiserror::Error;
+
+use crate::nix_parse::getkey;
+
+#[derive(Error, Debug)]
+pub(crate) enum FlakeError {
+    #[error("could not read {0}")]
+    CouldNotReadFlake(PathBuf, #[source] io::Error),
+    #[error("flake.nix does not define any homeConfigurations")]
+    NoHomeConfigurations,
+    #[error("flake.nix does not define homeConfigurations.\"{0}\"")]
+    NoSuchHomeConfiguration(String),
+    #[error("could not pick a home configuration from flake.nix; specify one with --flake <dir>#<name>")]
+    AmbiguousHomeConfiguration,
+    #[error("homeConfigurations.\"{0}\" does not list any module files")]
+    NoModules(String),
+}
+
+/// A Home Manager configuration defined in a flake
+pub(crate) struct Flake {
+    /// The directory that contains flake.nix
+    pub(crate) dir: PathBuf,
+    /// The name of the output in homeConfigurations
+    pub(crate) name: String,
+    /// The literal module paths listed in the configuration's `modules`
+    pub(crate) modules: Vec<PathBuf>,
+}
+
+impl Flake {
+    /// The argument to pass to home-manager switch --flake
+    pub(crate) fn uri(&self) -> String {
+        format!("{}#{}", self.dir.display(), self.name)
+    }
+}
+
+/// Splits a flake reference of the form `[dir][#name]` into its parts.
+pub(crate) fn parse_flake_ref(flake_ref: &str) -> (Option<PathBuf>, Option<String>) {
+    let (dir, name) = match flake_ref.rsplit_once('#') {
+        Some((dir, name)) => (dir, name),
+        None => (flake_ref, "")
+    };
+
+    let dir = if dir.is_empty() { None } else { Some(PathBuf::from(dir)) };
+    let name = if name.is_empty() { None } else { Some(name.trim_matches('"').to_string()) };
+    (dir, name)
+}
+
+/// Resolves the home configuration and its modules for the flake in `dir`.
+///
+/// If `name` is not given, this follows home-manager's own defaults:
+/// `$USER@hostname`, then `$USER`, then the only configuration if there is just one.
+pub(crate) fn resolve(dir: &Path, name: Option<String>) -> Result<Flake, FlakeError> {
+    use crate::flake::FlakeError::*;
+
+    let flake_path = dir.join("flake.nix");
+    let content = fs::read_to_string(&flake_path)
+        .map_err(|error| CouldNotReadFlake(flake_path, error))?;
+
+    let ast = rnix::Root::parse(&content);
+    let configurations = gethomeconfigurations(&ast.syntax());
+    if configurations.is_empty() {
+        return Err(NoHomeConfigurations);
+    }
+
+    let name = match name {
+        Some(name) => name,
+        None => default_name(&configurations)?
+    };
+    let configuration = configurations.get(&name)
+        .ok_or_else(|| NoSuchHomeConfiguration(name.clone()))?;
+
+    let modules: Vec<PathBuf> = getmodules(configuration)
+        .into_iter()
+        .map(|module| dir.join(module))
+        .collect();
+
+    if modules.is_empty() {
+        return Err(NoModules(name));
+    }
+
+    Ok(Flake {
+        dir: dir.to_path_buf(),
+        name,
+        modules,
+    })
+}
+
+fn default_name(configurations: &IndexMap<String, SyntaxNode>) -> Result<String, FlakeError> {
+    let user = std::env::var("USER").ok();
+    let hostname = Command::new("uname")
+        .arg("-n")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hostname| hostname.trim().to_string());
+
+    let mut candidates = vec![];
+    if let (Some(user), Some(hostname)) = (&user, &hostname) {
+        candidates.push(format!("{user}@{hostname}"));
+    }
+    if let Some(user) = &user {
+        candidates.push(user.clone());
+    }
+
+    for candidate in candidates {
+        if configurations.contains_key(&candidate) {
+            return Ok(candidate);
+        }
+    }
+
+    if configurations.len() == 1 {
+        return Ok(configurations.keys().next().unwrap().clone());
+    }
+    Err(FlakeError::AmbiguousHomeConfiguration)
+}
+
+fn unquote(key: &str) -> String {
+    key.trim_matches('"').to_string()
+}
+
+/// Finds every `homeConfigurations.<name> = ...;` binding in the flake,
+/// whether it is written with a dotted path or as a nested attribute set.
+fn gethomeconfigurations(root: &SyntaxNode) -> IndexMap<String, SyntaxNode> {
+    let mut out = IndexMap::new();
+    for node in root.descendants() {
+        if node.kind() != SyntaxKind::NODE_ATTRPATH_VALUE {
+            continue;
+        }
+        let (Some(attrpath), Some(value)) = (node.children().next(), node.last_child()) else {
+            continue;
+        };
+        let key = getkey(&attrpath);
+        if key.first().map(String::as_str) != Some("homeConfigurations") {
+            continue;
+        }
+        match key.len() {
+            1 if value.kind() == SyntaxKind::NODE_ATTR_SET => {
+                for child in value.children() {
+                    if child.kind() != SyntaxKind::NODE_ATTRPATH_VALUE {
+                        continue;
+                    }
+                    if let (Some(attrpath), Some(value)) = (child.children().next(), child.last_child()) {
+                        let key = getkey(&attrpath);
+                        if key.len() == 1 {
+                            out.insert(unquote(&key[0]), value);
+                        }
+                    }
+                }
+            }
+            2 => {
+                out.insert(unquote(&key[1]), value);
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Collects the literal paths in the `modules` list of a home configuration.
+fn getmodules(configuration: &SyntaxNode) -> Vec<String> {
+    let mut out = vec![];
+    for node in configuration.descendants() {
+        if node.kind() != SyntaxKind::NODE_ATTRPATH_VALUE {
+            continue;
+        }
+        let (Some(attrpath), Some(value)) = (node.children().next(), node.last_child()) else {
+            continue;
+        };
+        if getkey(&attrpath) != ["modules"] || value.kind() != SyntaxKind::NODE_LIST {
+            continue;
+        }
+        for elem in value.children() {
+            if elem.kind() == SyntaxKind::NODE_PATH {
+                out.push(elem.to_string());
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,111 @@
+use std::{fs, io};
+use std::collections::BTreeSet;
+use std::env::VarError;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::xdg;
+
+const DEFAULT_OPTIONS_COMMAND: [&str; 5] = ["nix", "build", "--no-link", "--print-out-paths", "home-manager#docs-json"];
+const OPTIONS_JSON_IN_OUTPUT: &str = "share/doc/home-manager/options.json";
+
+#[derive(Error, Debug)]
+pub(crate) enum OptionsError {
+    #[error("could not get $HOME environment variable")]
+    NoHomeEnvironmentVariable(#[source] VarError),
+    #[error("could not run {0} to build Home Manager's options; set options-json in hdn's config.toml instead")]
+    CouldNotRun(String, #[source] io::Error),
+    #[error("{0} returned a non-zero exit code")]
+    Unsuccessful(String),
+    #[error("could not read {0}")]
+    CouldNotRead(PathBuf, #[source] io::Error),
+    #[error("could not parse {0}")]
+    CouldNotParse(PathBuf, #[source] serde_json::Error),
+    #[error("could not write {0}")]
+    CouldNotWriteCache(PathBuf, #[source] io::Error),
+}
+
+/// Gets the names of the programs that Home Manager has a `programs.<name>.enable` option for.
+///
+/// The names are read from Home Manager's options.json, which is built on first use
+/// and cached under $XDG_CACHE_HOME/hdn; pass `refresh` to build it again.
+pub(crate) fn getprograms(config: &Config, refresh: bool) -> Result<BTreeSet<String>, OptionsError> {
+    use crate::hm_options::OptionsError::*;
+
+    if let Some(options_json) = &config.options_json {
+        return programs_from_options(options_json);
+    }
+
+    let cache = xdg::cache_home()
+        .map_err(NoHomeEnvironmentVariable)?
+        .join("hdn/programs.json");
+
+    if !refresh && cache.is_file() {
+        let content = fs::read_to_string(&cache)
+            .map_err(|error| CouldNotRead(cache.clone(), error))?;
+        return serde_json::from_str(&content)
+            .map_err(|error| CouldNotParse(cache, error));
+    }
+
+    let options_json = build_options_json(config)?;
+    let programs = programs_from_options(&options_json)?;
+
+    if let Some(dir) = cache.parent() {
+        fs::create_dir_all(dir).map_err(|error| CouldNotWriteCache(cache.clone(), error))?;
+    }
+    let content = serde_json::to_string(&programs)
+        .map_err(|error| CouldNotParse(cache.clone(), error))?;
+    fs::write(&cache, content).map_err(|error| CouldNotWriteCache(cache, error))?;
+
+    Ok(programs)
+}
+
+fn build_options_json(config: &Config) -> Result<PathBuf, OptionsError> {
+    use crate::hm_options::OptionsError::*;
+
+    let command: Vec<String> = match &config.options_command {
+        Some(command) => command.clone(),
+        None => DEFAULT_OPTIONS_COMMAND.iter().map(|s| s.to_string()).collect()
+    };
+    let display = command.join(" ");
+    let Some((program, args)) = command.split_first() else {
+        return Err(Unsuccessful(display));
+    };
+
+    eprintln!("Building Home Manager's options with {display}...");
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|error| CouldNotRun(display.clone(), error))?;
+    if !output.status.success() {
+        return Err(Unsuccessful(display));
+    }
+
+    let out_path = String::from_utf8_lossy(&output.stdout);
+    let out_path = Path::new(out_path.lines().next().unwrap_or("").trim());
+    if out_path.is_file() {
+        Ok(out_path.to_path_buf())
+    } else {
+        Ok(out_path.join(OPTIONS_JSON_IN_OUTPUT))
+    }
+}
+
+fn programs_from_options(options_json: &Path) -> Result<BTreeSet<String>, OptionsError> {
+    use crate::hm_options::OptionsError::*;
+
+    let content = fs::read_to_string(options_json)
+        .map_err(|error| CouldNotRead(options_json.to_path_buf(), error))?;
+    let options: Map<String, Value> = serde_json::from_str(&content)
+        .map_err(|error| CouldNotParse(options_json.to_path_buf(), error))?;
+
+    let programs = options.keys()
+        .filter_map(|option| {
+            let name = option.strip_prefix("programs.")?.strip_suffix(".enable")?;
+            (!name.contains('.')).then(|| name.trim_matches('"').to_string())
+        })
+        .collect();
+    Ok(programs)
+}
@@ -0,0 +1,83 @@
+use std::{env, fs, io};
+use std::path::{Path, PathBuf};
+use indexmap::IndexSet;
+use rnix::SyntaxKind;
+use thiserror::Error;
+
+use crate::nix_parse::{findattr, getcfgbase};
+
+#[derive(Error, Debug)]
+pub(crate) enum ImportsError {
+    #[error("could not read {0}")]
+    CouldNotReadModule(PathBuf, #[source] io::Error),
+}
+
+/// Walks the `imports` of each root module, recursively.
+///
+/// Returns every module file that can be reached, each once, in the order they are first reached.
+/// Only literal paths are followed; imports that don't exist are skipped,
+/// since home-manager switch will report those itself.
+pub(crate) fn getmodules(roots: &[PathBuf]) -> Result<IndexSet<PathBuf>, ImportsError> {
+    let mut modules = IndexSet::new();
+    for root in roots {
+        getmodules_aux(root, &mut modules)?;
+    }
+    Ok(modules)
+}
+
+fn getmodules_aux(module: &Path, modules: &mut IndexSet<PathBuf>) -> Result<(), ImportsError> {
+    let module = if module.is_dir() { module.join("default.nix") } else { module.to_path_buf() };
+    // Canonicalize so that a module reached through two different spellings is only walked once
+    let Ok(canonical) = module.canonicalize() else {
+        return Ok(());
+    };
+    if !canonical.is_file() || !modules.insert(canonical.clone()) {
+        return Ok(());
+    }
+
+    // Nix resolves relative imports next to the file a symlink points to, not next to the symlink
+    let dir = canonical.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let content = fs::read_to_string(&canonical)
+        .map_err(|error| ImportsError::CouldNotReadModule(canonical, error))?;
+
+    for import in getimports(&content) {
+        if let Some(import) = resolve(&dir, &import) {
+            getmodules_aux(&import, modules)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects the literal paths in the top-level `imports` list of a module.
+fn getimports(f: &str) -> Vec<String> {
+    let ast = rnix::Root::parse(f);
+    let Some(configbase) = getcfgbase(&ast.syntax()) else {
+        return vec![];
+    };
+    let Some(imports) = findattr(&configbase, "imports") else {
+        return vec![];
+    };
+
+    let mut out = vec![];
+    for child in imports.children() {
+        if child.kind() == SyntaxKind::NODE_LIST {
+            for elem in child.children() {
+                if elem.kind() == SyntaxKind::NODE_PATH {
+                    out.push(elem.to_string());
+                }
+            }
+        }
+    }
+    out
+}
+
+fn resolve(dir: &Path, import: &str) -> Option<PathBuf> {
+    if import.starts_with('<') {
+        // Lookup paths like <nixpkgs/...> are never user modules
+        return None;
+    }
+    if let Some(rest) = import.strip_prefix("~/") {
+        return env::var_os("HOME").map(|home| PathBuf::from(home).join(rest));
+    }
+    Some(dir.join(import))
+}
@@ -0,0 +1,216 @@
+use std::{fmt, fs, io};
+use std::collections::HashSet;
+use std::env::VarError;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use crate::{atomic, xdg};
+
+#[derive(Error, Debug)]
+pub(crate) enum JournalError {
+    #[error("could not get $HOME environment variable")]
+    NoHomeEnvironmentVariable(#[source] VarError),
+    #[error("could not read {0}")]
+    CouldNotRead(PathBuf, #[source] io::Error),
+    #[error("could not parse {0}")]
+    CouldNotParse(PathBuf, #[source] serde_json::Error),
+    #[error("could not write {0}")]
+    CouldNotWrite(PathBuf, #[source] io::Error),
+    #[error("there is no change {0} in the journal")]
+    NoEntry(u64),
+}
+
+/// What became of a change after the file was written
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Outcome {
+    /// home-manager switch succeeded
+    Switched,
+    /// home-manager switch failed, and the file was restored
+    RolledBack,
+    /// home-manager switch failed, and restoring the file failed too
+    RollbackFailed,
+    /// The file was written without running home-manager switch
+    NotSwitched,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use crate::journal::Outcome::*;
+
+        match self {
+            Switched => write!(f, "switched"),
+            RolledBack => write!(f, "rolled back"),
+            RollbackFailed => write!(f, "rollback failed"),
+            NotSwitched => write!(f, "not switched"),
+        }
+    }
+}
+
+/// One change hdn made to a module, kept as a line of $XDG_STATE_HOME/hdn/journal.jsonl.
+/// The content of the file before and after is kept in $XDG_STATE_HOME/hdn/snapshots, named by its hash.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Entry {
+    pub(crate) id: u64,
+    /// Seconds since the Unix epoch
+    pub(crate) timestamp: i64,
+    /// The subcommand that made the change, like `add`
+    pub(crate) command: String,
+    pub(crate) attr: String,
+    /// The packages that were changed, as written in the file, the value that was set,
+    /// or for hdn undo, the ids of the changes that were undone
+    pub(crate) packages: Vec<String>,
+    pub(crate) file: PathBuf,
+    pub(crate) before: String,
+    pub(crate) after: String,
+    pub(crate) outcome: Outcome,
+}
+
+impl Entry {
+    /// Whether the change is still in the file, which it isn't if the switch failed and it was rolled back
+    pub(crate) fn applied(&self) -> bool {
+        matches!(self.outcome, Outcome::Switched | Outcome::NotSwitched)
+    }
+}
+
+fn state_dir() -> Result<PathBuf, JournalError> {
+    Ok(xdg::state_home()
+        .map_err(JournalError::NoHomeEnvironmentVariable)?
+        .join("hdn"))
+}
+
+fn journal_file() -> Result<PathBuf, JournalError> {
+    Ok(state_dir()?.join("journal.jsonl"))
+}
+
+fn snapshot_file(hash: &str) -> Result<PathBuf, JournalError> {
+    Ok(state_dir()?.join("snapshots").join(format!("{hash}.nix")))
+}
+
+/// Gets the SHA-256 hash of the content, in hex.
+pub(crate) fn hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes()).iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Reads every entry in the journal, oldest first.
+pub(crate) fn entries() -> Result<Vec<Entry>, JournalError> {
+    use crate::journal::JournalError::*;
+
+    let path = journal_file()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => return Err(CouldNotRead(path, error))
+    };
+    content.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|error| CouldNotParse(path.clone(), error)))
+        .collect()
+}
+
+pub(crate) fn entry(id: u64) -> Result<Entry, JournalError> {
+    entries()?.into_iter()
+        .find(|entry| entry.id == id)
+        .ok_or(JournalError::NoEntry(id))
+}
+
+/// Finds the last `count` changes that can be undone, newest first:
+/// those that are still applied and haven't been undone already.
+/// Changes that a failed hdn switch rolled back were settled as rolled back, so they're skipped,
+/// and so are the records of hdn switch itself, which didn't change anything.
+pub(crate) fn undoable(count: usize) -> Result<Vec<Entry>, JournalError> {
+    let entries = entries()?;
+    let undone: HashSet<u64> = entries.iter()
+        .filter(|entry| entry.command == "undo" && entry.applied())
+        .flat_map(|entry| entry.packages.iter().filter_map(|id| id.parse().ok()))
+        .collect();
+    Ok(entries.into_iter()
+        .rev()
+        .filter(|entry| !matches!(entry.command.as_str(), "undo" | "switch"))
+        .filter(|entry| entry.applied() && !undone.contains(&entry.id))
+        .take(count)
+        .collect())
+}
+
+/// Reads the content of the file that has the hash, as it was kept by `record`.
+pub(crate) fn snapshot(hash: &str) -> Result<String, JournalError> {
+    let path = snapshot_file(hash)?;
+    fs::read_to_string(&path).map_err(|error| JournalError::CouldNotRead(path, error))
+}
+
+fn save_snapshot(content: &str) -> Result<String, JournalError> {
+    let hash = hash(content);
+    let path = snapshot_file(&hash)?;
+    if !path.exists() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|error| JournalError::CouldNotWrite(path.clone(), error))?;
+        }
+        fs::write(&path, content).map_err(|error| JournalError::CouldNotWrite(path, error))?;
+    }
+    Ok(hash)
+}
+
+/// Sets the outcome of the changes that were written to the files without switching,
+/// once a switch has activated them, or failed and rolled them back.
+pub(crate) fn settle(files: &[PathBuf], outcome: Outcome) -> Result<(), JournalError> {
+    use crate::journal::JournalError::*;
+
+    let mut entries = entries()?;
+    let mut changed = false;
+    for entry in entries.iter_mut() {
+        if entry.outcome == Outcome::NotSwitched && files.contains(&entry.file) {
+            entry.outcome = outcome;
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+
+    let path = journal_file()?;
+    let mut content = String::new();
+    for entry in &entries {
+        let line = serde_json::to_string(entry)
+            .map_err(|error| CouldNotParse(path.clone(), error))?;
+        content.push_str(&line);
+        content.push('\n');
+    }
+    atomic::write(&path, &content).map_err(|error| CouldNotWrite(path, error))
+}
+
+/// Appends a change to the journal, keeping snapshots of the file before and after it.
+pub(crate) fn record(command: &str, attr: &str, packages: &[String], file: &Path, before: &str, after: &str, outcome: Outcome) -> Result<Entry, JournalError> {
+    use crate::journal::JournalError::*;
+
+    let before = save_snapshot(before)?;
+    let after = save_snapshot(after)?;
+
+    let path = journal_file()?;
+    let id = entries()?.last().map_or(1, |entry| entry.id + 1);
+    let entry = Entry {
+        id,
+        timestamp: chrono::Local::now().timestamp(),
+        command: command.to_string(),
+        attr: attr.to_string(),
+        packages: packages.to_vec(),
+        file: file.to_path_buf(),
+        before,
+        after,
+        outcome,
+    };
+
+    let line = serde_json::to_string(&entry)
+        .map_err(|error| CouldNotParse(path.clone(), error))?;
+    let mut journal = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|error| CouldNotWrite(path.clone(), error))?;
+    writeln!(journal, "{line}").map_err(|error| CouldNotWrite(path, error))?;
+    Ok(entry)
+}
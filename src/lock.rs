@@ -0,0 +1,61 @@
+use std::{env, fs, io};
+use std::env::VarError;
+use std::fs::{File, TryLockError};
+use std::path::PathBuf;
+use thiserror::Error;
+use crate::xdg;
+
+#[derive(Error, Debug)]
+pub(crate) enum LockError {
+    #[error("could not get $HOME environment variable")]
+    NoHomeEnvironmentVariable(#[source] VarError),
+    #[error("could not open {0}")]
+    CouldNotOpen(PathBuf, #[source] io::Error),
+    #[error("could not lock {0}")]
+    CouldNotLock(PathBuf, #[source] io::Error),
+    #[error("another hdn is already running")]
+    Locked,
+}
+
+/// Keeps other hdn runs from editing home.nix or running home-manager switch until it's dropped
+pub(crate) struct Lock {
+    _file: File,
+}
+
+/// The lock file goes in $XDG_RUNTIME_DIR, which is cleared on logout,
+/// or else next to hdn's config.
+fn lock_file() -> Result<PathBuf, LockError> {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => Ok(PathBuf::from(dir).join("hdn.lock")),
+        _ => Ok(xdg::config_home()
+            .map_err(LockError::NoHomeEnvironmentVariable)?
+            .join("hdn/hdn.lock"))
+    }
+}
+
+/// Takes the lock, waiting for the hdn that holds it to finish unless `no_wait` is set.
+pub(crate) fn acquire(no_wait: bool) -> Result<Lock, LockError> {
+    use crate::lock::LockError::*;
+
+    let path = lock_file()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|error| CouldNotOpen(path.clone(), error))?;
+    }
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|error| CouldNotOpen(path.clone(), error))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) if no_wait => return Err(Locked),
+        Err(TryLockError::WouldBlock) => {
+            eprintln!("Waiting for another hdn to finish...");
+            file.lock().map_err(|error| CouldNotLock(path, error))?;
+        }
+        Err(TryLockError::Error(error)) => return Err(CouldNotLock(path, error))
+    }
+    Ok(Lock { _file: file })
+}
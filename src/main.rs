@@ -1,20 +1,33 @@
+mod activated;
+mod atomic;
+mod config;
+mod confirm;
 mod diff;
+mod flake;
+mod hm_options;
+mod imports;
+mod journal;
+mod lock;
 mod nix_parse;
 mod nix_read;
 mod nix_write;
+mod nixpkgs;
+mod xdg;
 
 use std::{fmt, fs, io, env};
+use std::io::{IsTerminal, Write};
 use std::env::VarError;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitCode};
 use owo_colors::{OwoColorize};
-use clap::{Parser, Subcommand};
-use indexmap::IndexSet;
+use clap::{Args, Parser, Subcommand};
+use indexmap::{IndexMap, IndexSet};
 use thiserror::Error;
 
-const QUERY: &str = "home.packages";
+const DEFAULT_ATTR: &str = "home.packages";
+const DEFAULT_LINE_WIDTH: usize = 100;
 
 #[derive(Subcommand)]
 enum HdnSubcommand {
@@ -22,17 +35,198 @@ enum HdnSubcommand {
     Add {
         /// The packages to add, space separated
         packages: Vec<String>,
-        /// Passes --show-trace to home-manager switch
-        #[clap(long, short, action)]
-        show_trace: bool
+        /// Don't check that the packages exist in nixpkgs
+        #[clap(long, action)]
+        no_check: bool,
+        #[command(flatten)]
+        insert: InsertArgs,
+        #[command(flatten)]
+        apply: ApplyArgs
     },
     /// Remove packages from home.nix, then run home-manager switch
     Remove {
         /// The packages to remove, space separated
         packages: Vec<String>,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// Comment packages out in home.nix, then run home-manager switch
+    Disable {
+        /// The packages to disable, space separated
+        packages: Vec<String>,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// Restore packages commented out by hdn disable, then run home-manager switch
+    Enable {
+        /// The packages to enable, space separated
+        packages: Vec<String>,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// Set an attribute in home.nix, then run home-manager switch
+    Set {
+        /// The attribute path, e.g. programs.git.userName
+        attrpath: String,
+        /// The value; anything other than a boolean, null or an integer is written as a string
+        value: String,
+        /// Write the value as a Nix expression, without quoting it
+        #[clap(long, action)]
+        expr: bool,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// Remove an attribute from home.nix, then run home-manager switch
+    Unset {
+        /// The attribute path, e.g. programs.git.userName
+        attrpath: String,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// Enable, disable, or list Home Manager programs
+    Program {
+        #[command(subcommand)]
+        action: ProgramAction
+    },
+    /// Run home-manager switch, rolling back to the last activated home.nix if it fails
+    Switch {
         /// Passes --show-trace to home-manager switch
         #[clap(long, short, action)]
         show_trace: bool
+    },
+    /// Search nixpkgs, using a cached index of its packages
+    Search {
+        /// The text to search for in package names and descriptions
+        query: String,
+        /// Rebuild the cached index before searching
+        #[clap(long, action)]
+        refresh: bool,
+        /// Build the index from a file with the output of nix-env -qaP --json --meta
+        #[clap(long, value_name = "PATH")]
+        from: Option<PathBuf>,
+        /// The maximum number of results to show
+        #[clap(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// List the packages in home.nix
+    List {
+        /// Print the packages as a JSON array
+        #[clap(long, action, conflicts_with = "count")]
+        json: bool,
+        /// Also list the packages commented out by hdn disable
+        #[clap(long, action)]
+        all: bool,
+        /// Print only the number of packages
+        #[clap(long, action)]
+        count: bool
+    },
+    /// Revert the last changes hdn made to home.nix, then run home-manager switch
+    Undo {
+        /// The number of changes to revert
+        #[clap(default_value_t = 1)]
+        count: usize,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// List the changes hdn has made to home.nix, or show one of them
+    History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>
+    }
+}
+
+#[derive(Subcommand)]
+enum HistoryAction {
+    /// Show the diff of a change
+    Show {
+        /// The number of the change, as listed by hdn history
+        id: u64
+    }
+}
+
+impl HdnSubcommand {
+    /// Whether the subcommand edits home.nix or runs home-manager switch,
+    /// so that it has to wait for any other hdn that does
+    fn needs_lock(&self) -> bool {
+        match self {
+            HdnSubcommand::Program { action } => !matches!(action, ProgramAction::List { .. }),
+            HdnSubcommand::Search { .. } | HdnSubcommand::List { .. } | HdnSubcommand::History { .. } => false,
+            _ => true
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum ProgramAction {
+    /// Set programs.<name>.enable = true in home.nix, then run home-manager switch
+    Enable {
+        /// The name of the program, e.g. git
+        name: String,
+        /// Don't check the name against Home Manager's options
+        #[clap(long, action)]
+        no_check: bool,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// Set programs.<name>.enable = false in home.nix, then run home-manager switch
+    Disable {
+        /// The name of the program, e.g. git
+        name: String,
+        /// Don't check the name against Home Manager's options
+        #[clap(long, action)]
+        no_check: bool,
+        #[command(flatten)]
+        apply: ApplyArgs
+    },
+    /// List the programs enabled in home.nix
+    List {
+        /// List every program Home Manager has options for instead
+        #[clap(long, action)]
+        available: bool,
+        /// Rebuild the cached list of Home Manager's programs
+        #[clap(long, action)]
+        refresh: bool
+    }
+}
+
+#[derive(Args)]
+struct ApplyArgs {
+    /// Passes --show-trace to home-manager switch
+    #[clap(long, short, action)]
+    show_trace: bool,
+    /// Show the diff without writing home.nix or running home-manager switch
+    #[clap(long, action)]
+    dry_run: bool,
+    /// Apply the changes without asking for confirmation
+    #[clap(long, short, action)]
+    yes: bool,
+    /// Write home.nix without running home-manager switch; run hdn switch later to activate it
+    #[clap(long, action, overrides_with = "switch")]
+    no_switch: bool,
+    /// Run home-manager switch even if no-switch is set in hdn's config.toml
+    #[clap(long, action, overrides_with = "no_switch")]
+    switch: bool,
+}
+
+impl ApplyArgs {
+    fn no_switch(&self, config: &config::Config) -> bool {
+        self.no_switch || (config.no_switch && !self.switch)
+    }
+}
+
+#[derive(Args)]
+struct InsertArgs {
+    /// If the list is sorted, insert the packages where they belong instead of at the end
+    #[clap(long, action, overrides_with = "no_sort")]
+    sort: bool,
+    /// Insert the packages at the end of the list even if sort is set in hdn's config.toml
+    #[clap(long, action, overrides_with = "sort")]
+    no_sort: bool,
+}
+
+impl InsertArgs {
+    fn sort(&self, config: &config::Config) -> bool {
+        self.sort || (config.sort && !self.no_sort)
     }
 }
 
@@ -43,6 +237,28 @@ enum HdnSubcommand {
 struct HdnCli {
     #[command(subcommand)]
     subcommand: HdnSubcommand,
+    #[command(flatten)]
+    home_dot_nix: HomeDotNixArgs,
+    /// The list attribute to edit, or the name of a preset from hdn's config.toml
+    #[arg(long, global = true, default_value = DEFAULT_ATTR)]
+    attr: String,
+    /// Fail right away if another hdn is running, instead of waiting for it to finish
+    #[arg(long, global = true)]
+    no_wait: bool,
+}
+
+#[derive(Args)]
+struct HomeDotNixArgs {
+    /// The home.nix to edit; it is also passed to home-manager switch -f
+    #[arg(long, global = true, env = "HDN_FILE", conflicts_with = "flake")]
+    file: Option<PathBuf>,
+    /// Use a flake-based setup, as [dir][#name]; the directory defaults to the detected flake,
+    /// and the name defaults to $USER@hostname or $USER
+    #[arg(long, global = true)]
+    flake: Option<String>,
+    /// The module to edit, when more than one imported file declares the attribute
+    #[arg(long, global = true)]
+    module: Option<PathBuf>,
 }
 
 #[derive(Error, Debug)]
@@ -55,11 +271,15 @@ enum RunHomeManagerSwitchError {
     Unsuccessful
 }
 
-fn run_home_manager_switch(show_trace: &bool) -> Result<(), RunHomeManagerSwitchError> {
+fn run_home_manager_switch(home_dot_nix: &HomeDotNix, show_trace: &bool) -> Result<(), RunHomeManagerSwitchError> {
     use crate::RunHomeManagerSwitchError::*;
 
     let mut command = Command::new("home-manager");
     let command = command.arg("switch");
+    let command = match &home_dot_nix.flake {
+        Some(flake) => command.arg("--flake").arg(flake.uri()),
+        None => command.arg("-f").arg(&home_dot_nix.path)
+    };
     let command = if *show_trace {command.arg("--show-trace")} else {command};
 
     let mut child = command
@@ -75,51 +295,97 @@ fn run_home_manager_switch(show_trace: &bool) -> Result<(), RunHomeManagerSwitch
 }
 
 enum UpdateNixMode {
-    Add,
-    Remove
+    Add { sort: bool, width: usize },
+    Remove,
+    Disable,
+    Enable
 }
 
 #[derive(Error, Debug)]
 enum UpdateNixError {
-    #[error("could not read values of home.packages attribute in home.nix")]
-    CouldNotReadNix(#[source] nix_read::ReadError),
-    #[error("could not write home.packages attribute for new packages")]
-    CouldNotWriteNix(#[source] nix_write::WriteError),
+    #[error("could not read values of {0} attribute in home.nix")]
+    CouldNotReadNix(String, #[source] nix_read::ReadError),
+    #[error("could not write {0} attribute")]
+    CouldNotWriteNix(String, #[source] nix_write::WriteError),
+    #[error("change {0} can't be undone automatically; see hdn history show {0}")]
+    CannotUndo(u64),
+    #[error("could not read the file as it was before change {0}")]
+    CouldNotReadSnapshot(u64, #[source] journal::JournalError),
 }
 
-fn update_nix(content: &str, packages: &Vec<String>, mode: &UpdateNixMode) -> Result<String, UpdateNixError> {
+/// Updates the list, returning the new content and the packages that changed, as they are written in it.
+fn update_nix(content: &str, attr: &str, packages: &[String], mode: &UpdateNixMode) -> Result<(String, Vec<String>), UpdateNixError> {
     use crate::UpdateNixError::*;
     use crate::UpdateNixMode::*;
 
-    let packages: IndexSet<&String> = IndexSet::from_iter(packages);
+    // When adding, a missing attribute is created by nix_write::addtoarr
+    let (existing_packages, scopes) = match nix_read::getarrvals(content, attr) {
+        Ok(values) => {
+            let scopes = nix_read::getwithscopes(content, attr)
+                .map_err(|error| CouldNotReadNix(attr.to_string(), error))?;
+            (values, scopes)
+        }
+        Err(nix_read::ReadError::NoAttr) if matches!(mode, Add { .. }) => (vec![], vec![]),
+        Err(error) => return Err(CouldNotReadNix(attr.to_string(), error))
+    };
 
-    let existing_packages: IndexSet<String> = IndexSet::from_iter(
-        nix_read::getarrvals(content, QUERY)
-            .map_err(CouldNotReadNix)?
-    );
+    // Compare packages by what they refer to, since `hello` inside `with pkgs;` is `pkgs.hello`
+    let existing_packages: IndexMap<String, String> = existing_packages.into_iter()
+        .map(|package| (nixpkgs::identity(&package, &scopes), package))
+        .collect();
+    let packages: IndexSet<String> = packages.iter()
+        .map(|package| nixpkgs::identity(package, &scopes))
+        .collect();
 
     match mode {
-        Add => {
-            let transformed_packages: Vec<&String> = packages.into_iter()
-                .filter(|&p| !existing_packages.contains(p))
+        Add { sort, width } => {
+            // Write the new packages in the style the list already uses
+            let transformed_packages: Vec<String> = packages.into_iter()
+                .filter(|p| !existing_packages.contains_key(p))
+                .map(|p| nixpkgs::qualify(&p, &scopes))
                 .collect();
 
-            nix_write::addtoarr(
+            let new_content = nix_write::addtoarr(
                 content,
-                QUERY,
-                transformed_packages.into_iter().cloned().collect()
-            ).map_err(CouldNotWriteNix)
+                attr,
+                transformed_packages.clone(),
+                *sort,
+                *width
+            ).map_err(|error| CouldNotWriteNix(attr.to_string(), error))?;
+            Ok((new_content, transformed_packages))
         }
-        Remove => {
-            let transformed_packages: Vec<&String> = packages.into_iter()
-                .filter(|&p| existing_packages.contains(p))
+        Remove | Disable => {
+            // Remove the elements as they are written in the list
+            let transformed_packages: Vec<String> = packages.iter()
+                .filter_map(|p| existing_packages.get(p))
+                .cloned()
                 .collect();
 
-            nix_write::rmarr(
+            let edit = if matches!(mode, Remove) { nix_write::rmarr } else { nix_write::disablearr };
+            let new_content = edit(
                 content,
-                QUERY,
-                transformed_packages.into_iter().cloned().collect()
-            ).map_err(CouldNotWriteNix)
+                attr,
+                transformed_packages.clone()
+            ).map_err(|error| CouldNotWriteNix(attr.to_string(), error))?;
+            Ok((new_content, transformed_packages))
+        }
+        Enable => {
+            let disabled_packages: IndexMap<String, String> = nix_read::getdisabled(content, attr)
+                .map_err(|error| CouldNotReadNix(attr.to_string(), error))?
+                .into_iter()
+                .map(|(package, _)| (nixpkgs::identity(&package, &scopes), package))
+                .collect();
+            let transformed_packages: Vec<String> = packages.iter()
+                .filter_map(|p| disabled_packages.get(p))
+                .cloned()
+                .collect();
+
+            let new_content = nix_write::enablearr(
+                content,
+                attr,
+                transformed_packages.clone()
+            ).map_err(|error| CouldNotWriteNix(attr.to_string(), error))?;
+            Ok((new_content, transformed_packages))
         }
     }
 }
@@ -129,20 +395,77 @@ enum GetHomeDotNixError {
     #[error("could not get $HOME environment variable")]
     NoHomeEnvironmentVariable(#[source] VarError),
     #[error("home.nix was not found in any of the default locations")]
-    NotFound
+    NotFound,
+    #[error("could not find the home-manager module to edit in the flake")]
+    CouldNotResolveFlake(#[source] flake::FlakeError),
+    #[error("{0} does not exist")]
+    ExplicitFileNotFound(PathBuf),
+    #[error("could not follow the imports of home.nix")]
+    CouldNotFollowImports(#[source] imports::ImportsError),
 }
 
-fn get_home_dot_nix() -> Result<PathBuf, GetHomeDotNixError> {
+/// The configuration that home-manager switch builds, and the module files it is made of
+struct HomeDotNix {
+    /// The file passed to home-manager switch -f; for flakes, this is flake.nix
+    path: PathBuf,
+    flake: Option<flake::Flake>,
+    /// Every module file reachable through imports
+    modules: IndexSet<PathBuf>,
+}
+
+fn get_home_dot_nix(args: &HomeDotNixArgs) -> Result<HomeDotNix, GetHomeDotNixError> {
+    let (path, flake) = find_home_dot_nix(args)?;
+
+    let roots = match &flake {
+        Some(flake) => flake.modules.clone(),
+        None => vec![path.clone()]
+    };
+    let modules = imports::getmodules(&roots)
+        .map_err(GetHomeDotNixError::CouldNotFollowImports)?;
+
+    Ok(HomeDotNix { path, flake, modules })
+}
+
+fn find_home_dot_nix(args: &HomeDotNixArgs) -> Result<(PathBuf, Option<flake::Flake>), GetHomeDotNixError> {
     use crate::GetHomeDotNixError::*;
 
-    let config_home = env::var("XDG_CONFIG_HOME");
-    let config_home: PathBuf = match config_home {
-        Ok(s) => PathBuf::from(s),
-        Err(_error) =>
-            [env::var("HOME").map_err(NoHomeEnvironmentVariable)?, ".config".to_string()]
-                .iter()
-                .collect()
+    // This follows home-manager's own lookup order,
+    // except that hdn's own --file and HDN_FILE come first.
+    if let Some(path) = &args.file {
+        return explicit_home_dot_nix(path);
+    }
+
+    let config_home = xdg::config_home().map_err(NoHomeEnvironmentVariable)?;
+
+    let flake_dirs_to_check = [
+        config_home.join("home-manager"),
+        config_home.join("nixpkgs"),
+    ];
+
+    let (flake_dir, flake_name) = match &args.flake {
+        Some(flake_ref) => flake::parse_flake_ref(flake_ref),
+        None => (None, None)
     };
+    // An empty HOME_MANAGER_CONFIG counts as unset, like the XDG variables
+    let home_manager_config = env::var_os("HOME_MANAGER_CONFIG")
+        .filter(|path| !path.is_empty())
+        .filter(|_| args.flake.is_none());
+    if let Some(path) = home_manager_config {
+        return explicit_home_dot_nix(&PathBuf::from(path));
+    }
+
+    let flake_dir = flake_dir.or_else(|| {
+        flake_dirs_to_check.into_iter().find(|dir| dir.join("flake.nix").exists())
+    });
+
+    if let Some(flake_dir) = flake_dir {
+        let flake = flake::resolve(&flake_dir, flake_name)
+            .map_err(CouldNotResolveFlake)?;
+        return Ok((flake_dir.join("flake.nix"), Some(flake)));
+    }
+    if args.flake.is_some() {
+        return Err(NotFound);
+    }
 
     let paths_to_check = [
         config_home.join("home-manager/home.nix"),
@@ -154,18 +477,114 @@ fn get_home_dot_nix() -> Result<PathBuf, GetHomeDotNixError> {
 
     for path in paths_to_check {
         if path.exists() {
-            return Ok(path);
+            return Ok((path, None));
         }
     }
 
     Err(NotFound)
 }
 
+fn explicit_home_dot_nix(path: &Path) -> Result<(PathBuf, Option<flake::Flake>), GetHomeDotNixError> {
+    if !path.is_file() {
+        return Err(GetHomeDotNixError::ExplicitFileNotFound(path.to_path_buf()));
+    }
+    Ok((path.to_path_buf(), None))
+}
+
+#[derive(Error, Debug)]
+enum ChooseModuleError {
+    #[error("none of the module files exist")]
+    NoModules,
+    #[error("{0} is not imported by home.nix")]
+    NotImported(PathBuf),
+    #[error("could not read {0}")]
+    CouldNotReadModule(PathBuf, #[source] io::Error),
+    #[error("{0} is declared in more than one file; choose one with --module")]
+    Ambiguous(String),
+    #[error("could not read the choice of file")]
+    CouldNotReadChoice(#[source] io::Error),
+}
+
+/// Finds the modules that declare the attribute.
+fn get_declaring_modules(home_dot_nix: &HomeDotNix, attr: &str) -> Result<Vec<PathBuf>, ChooseModuleError> {
+    let mut declaring = vec![];
+    for module in &home_dot_nix.modules {
+        let content = fs::read_to_string(module)
+            .map_err(|error| ChooseModuleError::CouldNotReadModule(module.clone(), error))?;
+        if nix_read::hasattr(&content, attr) {
+            declaring.push(module.clone());
+        }
+    }
+    Ok(declaring)
+}
+
+/// Picks the module file to edit.
+///
+/// This is the module given with --module if there is one,
+/// otherwise the only module that declares the attribute.
+/// If several modules declare it, the user is asked to choose when stdin is a terminal.
+/// If none do, the attribute is added to the top-level module.
+fn choose_module(home_dot_nix: &HomeDotNix, module: &Option<PathBuf>, attr: &str) -> Result<PathBuf, ChooseModuleError> {
+    use crate::ChooseModuleError::*;
+
+    if let Some(module) = module {
+        let canonical = module.canonicalize().unwrap_or(module.clone());
+        return home_dot_nix.modules.iter()
+            .find(|m| **m == canonical || m.ends_with(module))
+            .cloned()
+            .ok_or(NotImported(module.clone()));
+    }
+
+    let declaring = get_declaring_modules(home_dot_nix, attr)?;
+    match declaring.len() {
+        0 => home_dot_nix.modules.first().cloned().ok_or(NoModules),
+        1 => Ok(declaring[0].clone()),
+        _ if io::stdin().is_terminal() => prompt_for_module(&declaring, attr),
+        _ => Err(Ambiguous(attr.to_string()))
+    }
+}
+
+fn prompt_for_module(candidates: &[PathBuf], attr: &str) -> Result<PathBuf, ChooseModuleError> {
+    println!("{attr} is declared in more than one file:");
+    for (i, candidate) in candidates.iter().enumerate() {
+        println!("{:>4}) {}", i + 1, candidate.display());
+    }
+
+    loop {
+        print!("Which one should be edited? [1-{}] ", candidates.len());
+        io::stdout().flush().map_err(ChooseModuleError::CouldNotReadChoice)?;
+
+        let mut choice = String::new();
+        let read = io::stdin().read_line(&mut choice)
+            .map_err(ChooseModuleError::CouldNotReadChoice)?;
+        if read == 0 {
+            return Err(ChooseModuleError::Ambiguous(attr.to_string()));
+        }
+
+        if let Ok(i) = choice.trim().parse::<usize>() {
+            if (1..=candidates.len()).contains(&i) {
+                println!();
+                return Ok(candidates[i - 1].clone());
+            }
+        }
+    }
+}
+
 enum HdnSuccess {
     HomeManagerSwitchSucceeded,
     HomeManagerSwitchErroredButRollbackSuccessful,
     NothingToAdd,
-    NothingToRemove
+    NothingToRemove,
+    NothingToDisable,
+    NothingToEnable,
+    NothingToSet,
+    NothingToUnset,
+    NothingToUndo,
+    DryRun(PathBuf),
+    NotSwitched(PathBuf),
+    Cancelled,
+    Switched,
+    HomeManagerSwitchErroredAndNothingToRollBack
 }
 
 impl Display for HdnSuccess {
@@ -184,6 +603,36 @@ impl Display for HdnSuccess {
             NothingToRemove => {
                 write!(f, "home.nix doesn't contain any of the specified packages, home-manager switch was not run")
             }
+            NothingToDisable => {
+                write!(f, "home.nix doesn't contain any of the specified packages, home-manager switch was not run")
+            }
+            NothingToEnable => {
+                write!(f, "home.nix doesn't have any of the specified packages disabled, home-manager switch was not run")
+            }
+            NothingToSet => {
+                write!(f, "home.nix already sets the attribute to that value; home-manager switch was not run")
+            }
+            NothingToUnset => {
+                write!(f, "home.nix doesn't set the attribute; home-manager switch was not run")
+            }
+            NothingToUndo => {
+                write!(f, "There is nothing to undo; home-manager switch was not run")
+            }
+            DryRun(file) => {
+                write!(f, "Dry run; {} was not changed and home-manager switch was not run", file.display())
+            }
+            NotSwitched(file) => {
+                write!(f, "Successfully updated {}; home-manager switch was not run, so run hdn switch to activate it", file.display())
+            }
+            Cancelled => {
+                write!(f, "Cancelled; home.nix was not changed and home-manager switch was not run")
+            }
+            Switched => {
+                write!(f, "Successfully activated generation")
+            }
+            HomeManagerSwitchErroredAndNothingToRollBack => {
+                write!(f, "Running home-manager switch errored; there were no changes since the last activated home.nix to roll back")
+            }
         }
     }
 }
@@ -198,34 +647,110 @@ enum HdnError {
     CouldNotWriteToFile(#[source] io::Error),
     #[error("running home-manager switch errored, and during the rollback of home.nix, another error occurred")]
     UnsuccessfulAndNotRolledBack(#[source] io::Error),
-    #[error("could not update home.packages attribute in home.nix")]
+    #[error("running home-manager switch errored, and the last activated home.nix could not be read")]
+    UnsuccessfulAndNoActivatedState(#[source] activated::ActivatedError),
+    #[error("could not record the activated home.nix")]
+    CouldNotRecordActivated(#[source] activated::ActivatedError),
+    #[error("could not update home.nix")]
     CouldNotUpdatePackages(#[source] UpdateNixError),
+    #[error("could not confirm the changes")]
+    CouldNotConfirm(#[source] confirm::ConfirmError),
+    #[error("could not read values of {0} attribute in home.nix")]
+    CouldNotReadPackages(String, #[source] nix_read::ReadError),
+    #[error("could not choose which module to edit")]
+    CouldNotChooseModule(#[source] ChooseModuleError),
+    #[error("could not get the programs Home Manager has options for")]
+    CouldNotGetPrograms(#[source] hm_options::OptionsError),
+    #[error("Home Manager has no option programs.{0}.enable")]
+    UnknownProgram(String),
+    #[error("could not read the attributes in home.nix")]
+    CouldNotReadBindings(#[source] nix_read::ReadError),
+    #[error("{0} does not exist in nixpkgs")]
+    UnknownPackage(String),
+    #[error("some packages do not exist in nixpkgs; home.nix was not changed")]
+    UnknownPackages,
+    #[error("could not check whether the packages exist in nixpkgs")]
+    CouldNotCheckPackages(#[source] nixpkgs::EvalError),
+    #[error("could not load hdn's configuration")]
+    CouldNotLoadConfig(#[source] config::ConfigError),
+    #[error("could not serialize the package list")]
+    CouldNotSerializePackages(#[source] serde_json::Error),
+    #[error("could not get the package index")]
+    CouldNotGetIndex(#[source] nixpkgs::IndexError),
+    #[error("could not lock out other hdn runs")]
+    CouldNotLock(#[source] lock::LockError),
+    #[error("could not record the change in the journal")]
+    CouldNotRecordJournal(#[source] journal::JournalError),
+    #[error("could not read the journal")]
+    CouldNotReadJournal(#[source] journal::JournalError),
+    #[error("the changes to undo were made to more than one file; undo fewer of them at a time")]
+    UndoSpansModules,
 }
 
-fn update(mode: UpdateNixMode, packages: &Vec<String>, show_trace: &bool) -> Result<HdnSuccess, HdnError> {
+fn update(mode: UpdateNixMode, packages: &[String], apply_args: &ApplyArgs, args: &HomeDotNixArgs, attr: &str, config: &config::Config) -> Result<HdnSuccess, HdnError> {
+    let (command, unchanged) = match mode {
+        UpdateNixMode::Add { .. } => ("add", HdnSuccess::NothingToAdd),
+        UpdateNixMode::Remove => ("remove", HdnSuccess::NothingToRemove),
+        UpdateNixMode::Disable => ("disable", HdnSuccess::NothingToDisable),
+        UpdateNixMode::Enable => ("enable", HdnSuccess::NothingToEnable)
+    };
+    apply(args, attr, apply_args, config, command, unchanged, |content| {
+        update_nix(content, attr, packages, &mode)
+    })
+}
+
+/// Applies `change` to the module that declares `attr` and prints the diff,
+/// then runs home-manager switch, rolling the module back if the switch fails.
+/// `change` also returns what changed, which is recorded in the journal along with `command`.
+fn apply<F>(args: &HomeDotNixArgs, attr: &str, apply_args: &ApplyArgs, config: &config::Config, command: &str, unchanged: HdnSuccess, change: F) -> Result<HdnSuccess, HdnError>
+where F: FnOnce(&str) -> Result<(String, Vec<String>), UpdateNixError> {
     use crate::HdnError::*;
     use crate::HdnSuccess::*;
 
-    let file = get_home_dot_nix().map_err(CouldNotFindHomeDotNix)?;
+    let home_dot_nix = get_home_dot_nix(args).map_err(CouldNotFindHomeDotNix)?;
+    let file = &choose_module(&home_dot_nix, &args.module, attr).map_err(CouldNotChooseModule)?;
 
-    let content = fs::read_to_string(&file).map_err(CouldNotReadFile)?;
+    let content = fs::read_to_string(file).map_err(CouldNotReadFile)?;
 
-    let new_content = update_nix(&content, packages, &mode)
+    let (new_content, changed) = change(&content)
         .map_err(CouldNotUpdatePackages)?;
+    let record = |new_content: &str, outcome| record_journal(command, attr, &changed, file, &content, new_content, outcome);
 
     if new_content.eq(&content) {
-        return match mode {
-            UpdateNixMode::Add => Ok(NothingToAdd),
-            UpdateNixMode::Remove => Ok(NothingToRemove)
-        };
+        return Ok(unchanged);
     }
 
     diff::print_diff(&content, &new_content);
     println!();
 
-    fs::write(&file, new_content).map_err(CouldNotWriteToFile)?;
+    if apply_args.dry_run {
+        return Ok(DryRun(file.clone()));
+    }
+
+    let new_content = if apply_args.yes || !io::stdin().is_terminal() {
+        new_content
+    } else {
+        match confirm::confirm(&content, new_content).map_err(CouldNotConfirm)? {
+            Some(new_content) => new_content,
+            None => return Ok(Cancelled)
+        }
+    };
+
+    if apply_args.no_switch(config) {
+        // Remember what the file looked like before it was first edited without switching,
+        // so hdn switch has something to roll back to
+        let mut activated = activated::Activated::load().map_err(CouldNotRecordActivated)?;
+        activated.insert_if_absent(file, &content);
+        activated.save().map_err(CouldNotRecordActivated)?;
+
+        atomic::write(file, &new_content).map_err(CouldNotWriteToFile)?;
+        record(&new_content, journal::Outcome::NotSwitched);
+        return Ok(NotSwitched(file.clone()));
+    }
 
-    let run_result = run_home_manager_switch(show_trace);
+    atomic::write(file, &new_content).map_err(CouldNotWriteToFile)?;
+
+    let run_result = run_home_manager_switch(&home_dot_nix, &apply_args.show_trace);
     if let Err(error) = run_result {
         // Skip printing the error if home-manager returned a non-zero exit code,
         // since home-manager prints its own errors.
@@ -234,21 +759,581 @@ fn update(mode: UpdateNixMode, packages: &Vec<String>, show_trace: &bool) -> Res
         }
         println!();
 
-        fs::write(&file, content)
-            .map_err(UnsuccessfulAndNotRolledBack)?;
+        if let Err(error) = atomic::write(file, &content) {
+            record(&new_content, journal::Outcome::RollbackFailed);
+            return Err(UnsuccessfulAndNotRolledBack(error));
+        }
 
+        record(&new_content, journal::Outcome::RolledBack);
         return Ok(HomeManagerSwitchErroredButRollbackSuccessful);
     }
     println!();
+    record_activated(&home_dot_nix);
+    // The switch also activated any changes made earlier without switching
+    settle_journal(&home_dot_nix.modules, journal::Outcome::Switched);
+    record(&new_content, journal::Outcome::Switched);
     Ok(HomeManagerSwitchSucceeded)
 }
 
-fn add(packages: &Vec<String>, show_trace: &bool) -> Result<HdnSuccess, HdnError> {
-    update(UpdateNixMode::Add, packages, show_trace)
+/// Sets the outcome of the changes to the modules that were made without switching.
+/// Like record_journal, errors are only printed.
+fn settle_journal(modules: &IndexSet<PathBuf>, outcome: journal::Outcome) {
+    let modules: Vec<PathBuf> = modules.iter().cloned().collect();
+    if let Err(error) = journal::settle(&modules, outcome) {
+        print_error(HdnError::CouldNotRecordJournal(error));
+    }
+}
+
+/// Records a change in the journal.
+/// The file has already been written at this point, so errors are only printed.
+fn record_journal(command: &str, attr: &str, packages: &[String], file: &Path, before: &str, after: &str, outcome: journal::Outcome) {
+    if let Err(error) = journal::record(command, attr, packages, file, before, after, outcome) {
+        print_error(HdnError::CouldNotRecordJournal(error));
+    }
+}
+
+/// Records the content of every module as activated, so that hdn switch can roll back to it.
+/// The switch has already succeeded at this point, so errors are only printed.
+fn record_activated(home_dot_nix: &HomeDotNix) {
+    let result = activated::Activated::load().and_then(|mut activated| {
+        for module in &home_dot_nix.modules {
+            if let Ok(content) = fs::read_to_string(module) {
+                activated.insert(module, content);
+            }
+        }
+        activated.save()
+    });
+    if let Err(error) = result {
+        print_error(HdnError::CouldNotRecordActivated(error));
+    }
+}
+
+fn switch(show_trace: &bool, args: &HomeDotNixArgs) -> Result<HdnSuccess, HdnError> {
+    use crate::HdnError::*;
+    use crate::HdnSuccess::*;
+
+    let home_dot_nix = get_home_dot_nix(args).map_err(CouldNotFindHomeDotNix)?;
+
+    let run_result = run_home_manager_switch(&home_dot_nix, show_trace);
+    if let Err(error) = run_result {
+        // Skip printing the error if home-manager returned a non-zero exit code,
+        // since home-manager prints its own errors.
+        if !matches!(error, RunHomeManagerSwitchError::Unsuccessful) {
+            print_error(error);
+        }
+        println!();
+
+        // Roll back every module that changed since it was last activated
+        let activated = activated::Activated::load().map_err(UnsuccessfulAndNoActivatedState)?;
+        let mut rolled_back = IndexSet::new();
+        for module in &home_dot_nix.modules {
+            let Some(previous) = activated.get(module) else {
+                continue;
+            };
+            let current = fs::read_to_string(module).map_err(UnsuccessfulAndNotRolledBack)?;
+            if current != *previous {
+                diff::print_diff(&current, previous);
+                println!();
+                if let Err(error) = atomic::write(module, previous) {
+                    record_journal("switch", "", &[], module, &current, previous, journal::Outcome::RollbackFailed);
+                    settle_journal(&rolled_back, journal::Outcome::RolledBack);
+                    return Err(UnsuccessfulAndNotRolledBack(error));
+                }
+                record_journal("switch", "", &[], module, &current, previous, journal::Outcome::RolledBack);
+                rolled_back.insert(module.clone());
+            }
+        }
+        settle_journal(&rolled_back, journal::Outcome::RolledBack);
+        let rolled_back = !rolled_back.is_empty();
+
+        return if rolled_back {
+            Ok(HomeManagerSwitchErroredButRollbackSuccessful)
+        } else {
+            Ok(HomeManagerSwitchErroredAndNothingToRollBack)
+        };
+    }
+    println!();
+
+    // Record what the switch activated in each module, before it's recorded as activated
+    let activated = activated::Activated::load().unwrap_or_default();
+    let mut changed = false;
+    for module in &home_dot_nix.modules {
+        let Ok(current) = fs::read_to_string(module) else {
+            continue;
+        };
+        if let Some(previous) = activated.get(module).filter(|previous| **previous != current) {
+            record_journal("switch", "", &[], module, previous, &current, journal::Outcome::Switched);
+            changed = true;
+        }
+    }
+    // Even if nothing changed, record that the switch happened
+    if !changed {
+        let path = home_dot_nix.path.canonicalize().unwrap_or(home_dot_nix.path.clone());
+        if let Ok(current) = fs::read_to_string(&path) {
+            record_journal("switch", "", &[], &path, &current, &current, journal::Outcome::Switched);
+        }
+    }
+    settle_journal(&home_dot_nix.modules, journal::Outcome::Switched);
+
+    record_activated(&home_dot_nix);
+    Ok(Switched)
+}
+
+fn add(packages: &[String], no_check: &bool, insert_args: &InsertArgs, apply_args: &ApplyArgs, args: &HomeDotNixArgs, attr: &str, config: &config::Config) -> Result<HdnSuccess, HdnError> {
+    // Only home.packages is checked, since other lists don't hold top-level nixpkgs attributes
+    if !*no_check && attr == DEFAULT_ATTR {
+        check_packages(packages, args, config)?;
+    }
+    let mode = UpdateNixMode::Add {
+        sort: insert_args.sort(config),
+        width: config.line_width.unwrap_or(DEFAULT_LINE_WIDTH),
+    };
+    update(mode, packages, apply_args, args, attr, config)
+}
+
+/// Checks that each package exists in the nixpkgs home-manager uses,
+/// printing an error for each one that doesn't.
+fn check_packages(packages: &[String], args: &HomeDotNixArgs, config: &config::Config) -> Result<(), HdnError> {
+    use crate::HdnError::*;
+
+    let home_dot_nix = get_home_dot_nix(args).map_err(CouldNotFindHomeDotNix)?;
+    let evaluator = nixpkgs::Evaluator::new(config, home_dot_nix.flake.as_ref());
+
+    let mut all_exist = true;
+    let mut index = None;
+    for package in packages {
+        let Some(attrpath) = nixpkgs::attrpath(package) else {
+            continue;
+        };
+        if !evaluator.exists(attrpath).map_err(CouldNotCheckPackages)? {
+            print_error(UnknownPackage(package.clone()));
+            all_exist = false;
+
+            // Suggestions are only a hint, so if the index can't be built, go without them
+            let index = index.get_or_insert_with(|| nixpkgs::getindex(config, false, None).unwrap_or_default());
+            let names = index.iter().map(|package| package.attr.as_str());
+            print_suggestions(package, attrpath, &nixpkgs::suggest(attrpath, names));
+        }
+    }
+
+    if !all_exist {
+        return Err(UnknownPackages);
+    }
+    Ok(())
+}
+
+/// Prints the suggestions for a package that doesn't exist,
+/// written with the same prefix the user gave, like `pkgs.nodejs_20` for `pkgs.nodejs`.
+fn print_suggestions(package: &str, attrpath: &str, suggestions: &[&str]) {
+    if suggestions.is_empty() {
+        return;
+    }
+    let prefix = package.strip_suffix(attrpath).unwrap_or("");
+    let suggestions: Vec<String> = suggestions.iter()
+        .map(|suggestion| format!("{prefix}{suggestion}"))
+        .collect();
+    eprintln!("{} did you mean {}?", "hint:".cyan().bold(), suggestions.join(", "));
+}
+
+fn remove(packages: &[String], apply_args: &ApplyArgs, args: &HomeDotNixArgs, attr: &str, config: &config::Config) -> Result<HdnSuccess, HdnError> {
+    // Point out packages that aren't in the list, with the closest ones that are
+    let current = get_packages(args, attr)?;
+    for package in packages {
+        let Some(attrpath) = nixpkgs::attrpath(package) else {
+            continue;
+        };
+        if current.iter().any(|current| nixpkgs::attrpath(current) == Some(attrpath)) {
+            continue;
+        }
+        eprintln!("{} {package} is not in {attr}", "note:".yellow().bold());
+        // Suggest the entries as they are written in the list
+        let names = current.iter().filter_map(|current| nixpkgs::attrpath(current));
+        let suggestions: Vec<&str> = nixpkgs::suggest(attrpath, names).into_iter()
+            .filter_map(|suggestion| current.iter().find(|current| nixpkgs::attrpath(current) == Some(suggestion)))
+            .map(String::as_str)
+            .collect();
+        print_suggestions("", "", &suggestions);
+    }
+
+    update(UpdateNixMode::Remove, packages, apply_args, args, attr, config)
+}
+
+/// Writes a value from the command line as Nix:
+/// booleans, null and integers are written as-is, and anything else as a string.
+/// Floats are written as strings too, since values like home.stateVersion = "23.11" look like them.
+fn to_nix_value(value: &str) -> String {
+    if matches!(value, "true" | "false" | "null") || value.parse::<i64>().is_ok() {
+        value.to_string()
+    } else {
+        nix_write::quote(value)
+    }
+}
+
+fn set(attrpath: &str, value: &str, expr: &bool, apply_args: &ApplyArgs, args: &HomeDotNixArgs, config: &config::Config) -> Result<HdnSuccess, HdnError> {
+    let value = if *expr { value.to_string() } else { to_nix_value(value) };
+
+    apply(args, attrpath, apply_args, config, "set", HdnSuccess::NothingToSet, |content| {
+        let new_content = nix_write::setval(content, attrpath, &value)
+            .map_err(|error| UpdateNixError::CouldNotWriteNix(attrpath.to_string(), error))?;
+        Ok((new_content, vec![value.clone()]))
+    })
+}
+
+fn unset(attrpath: &str, apply_args: &ApplyArgs, args: &HomeDotNixArgs, config: &config::Config) -> Result<HdnSuccess, HdnError> {
+    apply(args, attrpath, apply_args, config, "unset", HdnSuccess::NothingToUnset, |content| {
+        if !nix_read::hasattr(content, attrpath) {
+            return Ok((content.to_string(), vec![]));
+        }
+        let new_content = nix_write::rmval(content, attrpath)
+            .map_err(|error| UpdateNixError::CouldNotWriteNix(attrpath.to_string(), error))?;
+        Ok((new_content, vec![]))
+    })
+}
+
+fn program_enable(name: &str, enable: bool, no_check: &bool, apply_args: &ApplyArgs, args: &HomeDotNixArgs, config: &config::Config) -> Result<HdnSuccess, HdnError> {
+    if !*no_check {
+        let programs = hm_options::getprograms(config, false)
+            .map_err(HdnError::CouldNotGetPrograms)?;
+        if !programs.contains(name) {
+            return Err(HdnError::UnknownProgram(name.to_string()));
+        }
+    }
+
+    let attrpath = format!("programs.{name}.enable");
+    apply(args, &attrpath, apply_args, config, "set", HdnSuccess::NothingToSet, |content| {
+        let new_content = nix_write::setval(content, &attrpath, &enable.to_string())
+            .map_err(|error| UpdateNixError::CouldNotWriteNix(attrpath.clone(), error))?;
+        Ok((new_content, vec![enable.to_string()]))
+    })
+}
+
+fn program_list(available: &bool, refresh: &bool, args: &HomeDotNixArgs, config: &config::Config) -> Result<(), HdnError> {
+    use crate::HdnError::*;
+
+    if *available || *refresh {
+        let programs = hm_options::getprograms(config, *refresh)
+            .map_err(CouldNotGetPrograms)?;
+        if *available {
+            for program in programs {
+                println!("{program}");
+            }
+        }
+        return Ok(());
+    }
+
+    // Programs can be enabled in any module, so look through all of them
+    let home_dot_nix = get_home_dot_nix(args).map_err(CouldNotFindHomeDotNix)?;
+    let mut enabled = IndexSet::new();
+    for module in &home_dot_nix.modules {
+        let content = fs::read_to_string(module).map_err(CouldNotReadFile)?;
+        for (key, value) in nix_read::getbindings(&content).map_err(CouldNotReadBindings)? {
+            if let [programs, name, enable] = key.as_slice() {
+                if programs == "programs" && enable == "enable" && value == "true" {
+                    enabled.insert(name.clone());
+                }
+            }
+        }
+    }
+
+    for program in enabled {
+        println!("{program}");
+    }
+    Ok(())
+}
+
+/// Gets the values of the attribute, from the module given with --module,
+/// or else from every module that declares it, since home-manager merges them all.
+fn get_packages(args: &HomeDotNixArgs, attr: &str) -> Result<Vec<String>, HdnError> {
+    get_list_values(args, attr, nix_read::getarrvals)
+}
+
+/// Gets the packages commented out by hdn disable, from the same modules as get_packages.
+fn get_disabled_packages(args: &HomeDotNixArgs, attr: &str) -> Result<Vec<(String, Option<String>)>, HdnError> {
+    get_list_values(args, attr, nix_read::getdisabled)
+}
+
+/// Gets the packages along with the condition each one is under, like `stdenv.isLinux` for `lib.optionals stdenv.isLinux [ ... ]`.
+fn get_package_entries(args: &HomeDotNixArgs, attr: &str) -> Result<Vec<(String, Option<String>)>, HdnError> {
+    get_list_values(args, attr, nix_read::getarrentries)
+}
+
+fn get_list_values<T, F>(args: &HomeDotNixArgs, attr: &str, read: F) -> Result<Vec<T>, HdnError>
+where F: Fn(&str, &str) -> Result<Vec<T>, nix_read::ReadError> {
+    use crate::HdnError::*;
+
+    let home_dot_nix = get_home_dot_nix(args).map_err(CouldNotFindHomeDotNix)?;
+
+    let files = match args.module {
+        Some(_) => vec![choose_module(&home_dot_nix, &args.module, attr).map_err(CouldNotChooseModule)?],
+        None => {
+            let declaring = get_declaring_modules(&home_dot_nix, attr).map_err(CouldNotChooseModule)?;
+            if declaring.is_empty() {
+                vec![choose_module(&home_dot_nix, &None, attr).map_err(CouldNotChooseModule)?]
+            } else {
+                declaring
+            }
+        }
+    };
+
+    let mut packages = vec![];
+    for file in files {
+        let content = fs::read_to_string(file).map_err(CouldNotReadFile)?;
+        packages.extend(
+            read(&content, attr)
+                .map_err(|error| CouldNotReadPackages(attr.to_string(), error))?
+        );
+    }
+    Ok(packages)
+}
+
+fn search(query: &str, refresh: &bool, from: &Option<PathBuf>, limit: &usize, args: &HomeDotNixArgs, config: &config::Config) -> Result<(), HdnError> {
+    let index = nixpkgs::getindex(config, *refresh, from.as_deref())
+        .map_err(HdnError::CouldNotGetIndex)?;
+
+    // Searching doesn't need home.nix, so if it can't be read, just don't mark anything as installed
+    let installed: IndexSet<String> = get_packages(args, DEFAULT_ATTR)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|package| nixpkgs::attrpath(package))
+        .map(str::to_string)
+        .collect();
+
+    for package in nixpkgs::search(&index, query).into_iter().take(*limit) {
+        let mut line = format!("* {}", format!("pkgs.{}", package.attr).bold());
+        if !package.version.is_empty() {
+            line.push_str(&format!(" ({})", package.version));
+        }
+        if installed.contains(&package.attr) {
+            line.push_str(&format!(" {}", "[installed]".green()));
+        }
+        println!("{line}");
+        if !package.description.is_empty() {
+            println!("  {}", package.description);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn list(json: &bool, all: &bool, count: &bool, args: &HomeDotNixArgs, attr: &str) -> Result<(), HdnError> {
+    let entries = get_package_entries(args, attr)?;
+
+    if *all {
+        let disabled = get_disabled_packages(args, attr)?;
+        return list_all(json, count, entries, disabled);
+    }
+
+    if *count {
+        println!("{}", entries.len());
+    } else if *json {
+        let packages: Vec<&String> = entries.iter().map(|(package, _)| package).collect();
+        let output = serde_json::to_string(&packages)
+            .map_err(HdnError::CouldNotSerializePackages)?;
+        println!("{output}");
+    } else {
+        for (package, condition) in entries {
+            print_package(&package, &condition);
+        }
+    }
+    Ok(())
+}
+
+/// Prints a package, and the condition it is under if there is one.
+fn print_package(package: &str, condition: &Option<String>) {
+    match condition {
+        Some(condition) => println!("{package} {}", format!("(if {condition})").dimmed()),
+        None => println!("{package}"),
+    }
+}
+
+/// Lists the packages along with the disabled ones, which are marked as such.
+/// As JSON, each package is an object like `{"package": "pkgs.hello", "disabled": false, "condition": null}`.
+fn list_all(json: &bool, count: &bool, entries: Vec<(String, Option<String>)>, disabled: Vec<(String, Option<String>)>) -> Result<(), HdnError> {
+    if *count {
+        println!("{}", entries.len() + disabled.len());
+    } else if *json {
+        let entries: Vec<serde_json::Value> = entries.iter()
+            .map(|(package, condition)| serde_json::json!({ "package": package, "disabled": false, "condition": condition }))
+            .chain(disabled.iter().map(|(package, condition)| serde_json::json!({ "package": package, "disabled": true, "condition": condition })))
+            .collect();
+        let output = serde_json::to_string(&entries)
+            .map_err(HdnError::CouldNotSerializePackages)?;
+        println!("{output}");
+    } else {
+        for (package, condition) in entries {
+            print_package(&package, &condition);
+        }
+        for (package, condition) in disabled {
+            match condition {
+                Some(condition) => println!("{package} {}", format!("(disabled, if {condition})").dimmed()),
+                None => println!("{package} {}", "(disabled)".dimmed()),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn undo(count: &usize, apply_args: &ApplyArgs, args: &HomeDotNixArgs, config: &config::Config) -> Result<HdnSuccess, HdnError> {
+    use crate::HdnError::*;
+
+    let entries = journal::undoable(*count).map_err(CouldNotReadJournal)?;
+    let Some(last) = entries.first() else {
+        return Ok(HdnSuccess::NothingToUndo);
+    };
+    if entries.iter().any(|entry| entry.file != last.file) {
+        return Err(UndoSpansModules);
+    }
+
+    // Edit the module the changes were made to, wherever the attribute is declared now
+    let args = HomeDotNixArgs {
+        file: args.file.clone(),
+        flake: args.flake.clone(),
+        module: Some(last.file.clone()),
+    };
+    let ids: Vec<String> = entries.iter().map(|entry| entry.id.to_string()).collect();
+    apply(&args, &last.attr, apply_args, config, "undo", HdnSuccess::NothingToUndo, |content| {
+        let mut content = content.to_string();
+        for entry in &entries {
+            content = undo_change(&content, entry, config)?;
+        }
+        Ok((content, ids))
+    })
+}
+
+/// Applies the inverse of a change to the file as it is now, so that edits made since then are kept.
+fn undo_change(content: &str, entry: &journal::Entry, config: &config::Config) -> Result<String, UpdateNixError> {
+    use crate::UpdateNixError::*;
+
+    let mode = match entry.command.as_str() {
+        "add" => UpdateNixMode::Remove,
+        "remove" => return undo_remove(content, entry, config),
+        "disable" => UpdateNixMode::Enable,
+        "enable" => UpdateNixMode::Disable,
+        "set" | "unset" => return undo_set(content, entry),
+        _ => return Err(CannotUndo(entry.id))
+    };
+    // The whole list may have been deleted since, taking the added packages with it
+    if matches!(mode, UpdateNixMode::Remove) && !nix_read::hasattr(content, &entry.attr) {
+        return Ok(content.to_string());
+    }
+    update_nix(content, &entry.attr, &entry.packages, &mode).map(|(content, _)| content)
+}
+
+/// Undoes hdn remove by adding the packages back to the lists they were removed from.
+/// The condition each one was under is read from the journal's snapshot of the file before the change,
+/// so `strace` removed from `lib.optionals stdenv.isLinux [ ... ]` goes back there.
+fn undo_remove(content: &str, entry: &journal::Entry, config: &config::Config) -> Result<String, UpdateNixError> {
+    use crate::UpdateNixError::*;
+
+    let before = journal::snapshot(&entry.before)
+        .map_err(|error| CouldNotReadSnapshot(entry.id, error))?;
+    let conditions: IndexMap<String, Option<String>> = nix_read::getarrentries(&before, &entry.attr)
+        .map_err(|error| CouldNotReadNix(entry.attr.clone(), error))?
+        .into_iter()
+        .collect();
+
+    let sort = config.sort;
+    let width = config.line_width.unwrap_or(DEFAULT_LINE_WIDTH);
+    let mut unconditional = vec![];
+    let mut conditional: IndexMap<String, Vec<String>> = IndexMap::new();
+    for package in &entry.packages {
+        match conditions.get(package).cloned().flatten() {
+            Some(condition) => conditional.entry(condition).or_default().push(package.clone()),
+            None => unconditional.push(package.clone()),
+        }
+    }
+
+    let mut content = content.to_string();
+    if !unconditional.is_empty() {
+        content = update_nix(&content, &entry.attr, &unconditional, &UpdateNixMode::Add { sort, width })?.0;
+    }
+    for (condition, packages) in conditional {
+        // Skip the packages that were added back by hand
+        let existing = nix_read::getarrvals(&content, &entry.attr)
+            .map_err(|error| CouldNotReadNix(entry.attr.clone(), error))?;
+        let packages: Vec<String> = packages.into_iter()
+            .filter(|package| !existing.contains(package))
+            .collect();
+        if packages.is_empty() {
+            continue;
+        }
+        content = nix_write::addtocond(&content, &entry.attr, packages, &condition, sort, width)
+            .map_err(|error| CouldNotWriteNix(entry.attr.clone(), error))?;
+    }
+    Ok(content)
+}
+
+/// Undoes hdn set or hdn unset by setting the value the attribute had before,
+/// which is read from the journal's snapshot, or by removing it if it wasn't set.
+fn undo_set(content: &str, entry: &journal::Entry) -> Result<String, UpdateNixError> {
+    use crate::UpdateNixError::*;
+
+    let before = journal::snapshot(&entry.before)
+        .map_err(|error| CouldNotReadSnapshot(entry.id, error))?;
+    match nix_read::getval(&before, &entry.attr) {
+        Ok(value) => nix_write::setval(content, &entry.attr, &value)
+            .map_err(|error| CouldNotWriteNix(entry.attr.clone(), error)),
+        Err(nix_read::ReadError::NoAttr) if entry.command == "set" && !nix_read::hasattr(&before, &entry.attr) => {
+            if !nix_read::hasattr(content, &entry.attr) {
+                return Ok(content.to_string());
+            }
+            nix_write::rmval(content, &entry.attr)
+                .map_err(|error| CouldNotWriteNix(entry.attr.clone(), error))
+        }
+        Err(_) => Err(CannotUndo(entry.id))
+    }
+}
+
+/// Describes a change like the command that made it, such as `add pkgs.hello` or `set programs.git.enable true`.
+fn describe_change(entry: &journal::Entry) -> String {
+    let mut words = vec![entry.command.clone()];
+    let is_list = !matches!(entry.command.as_str(), "set" | "unset" | "undo" | "switch");
+    if matches!(entry.command.as_str(), "set" | "unset") {
+        words.push(entry.attr.clone());
+    }
+    words.extend(entry.packages.iter().cloned());
+    if is_list && entry.attr != DEFAULT_ATTR {
+        words.push(format!("--attr {}", entry.attr));
+    }
+    words.join(" ")
 }
 
-fn remove(packages: &Vec<String>, show_trace: &bool) -> Result<HdnSuccess, HdnError> {
-    update(UpdateNixMode::Remove, packages, show_trace)
+fn format_timestamp(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string(),
+        None => timestamp.to_string()
+    }
+}
+
+fn history() -> Result<(), HdnError> {
+    let entries = journal::entries().map_err(HdnError::CouldNotReadJournal)?;
+    for entry in entries {
+        println!(
+            "{} {} {} {} {}",
+            format!("{:>4}", entry.id).bold(),
+            format_timestamp(entry.timestamp).dimmed(),
+            describe_change(&entry),
+            entry.file.display().dimmed(),
+            format!("({})", entry.outcome).dimmed()
+        );
+    }
+    Ok(())
+}
+
+/// Prints the diff of a change, from the snapshots the journal kept of the file.
+fn history_show(id: &u64) -> Result<(), HdnError> {
+    use crate::HdnError::*;
+
+    let entry = journal::entry(*id).map_err(CouldNotReadJournal)?;
+    let before = journal::snapshot(&entry.before).map_err(CouldNotReadJournal)?;
+    let after = journal::snapshot(&entry.after).map_err(CouldNotReadJournal)?;
+
+    println!("{} {}", format!("{}", entry.id).bold(), describe_change(&entry));
+    println!("{} {}", format_timestamp(entry.timestamp).dimmed(), format!("({})", entry.outcome).dimmed());
+    println!("{}", entry.file.display());
+    println!();
+    diff::print_diff(&before, &after);
+    Ok(())
 }
 
 fn print_error<T: Error>(error: T) {
@@ -265,15 +1350,90 @@ fn print_error<T: Error>(error: T) {
 }
 
 fn main() -> ExitCode {
+    // An empty HDN_FILE counts as unset, like HOME_MANAGER_CONFIG, rather than as a --file with no value
+    if env::var_os("HDN_FILE").is_some_and(|path| path.is_empty()) {
+        env::remove_var("HDN_FILE");
+    }
     let cli = HdnCli::parse();
 
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            print_error(HdnError::CouldNotLoadConfig(error));
+            return ExitCode::FAILURE;
+        }
+    };
+    let attr = &config.resolve_attr(&cli.attr);
+
+    // Held until hdn exits, so the whole transaction from reading home.nix to rolling it back is done alone
+    let _lock = if cli.subcommand.needs_lock() {
+        match lock::acquire(cli.no_wait) {
+            Ok(lock) => Some(lock),
+            Err(error) => {
+                print_error(HdnError::CouldNotLock(error));
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
     let result = match &cli.subcommand {
-        HdnSubcommand::Add {packages, show_trace} => {
-            add(packages, show_trace)
+        HdnSubcommand::Add {packages, no_check, insert, apply} => {
+            add(packages, no_check, insert, apply, &cli.home_dot_nix, attr, &config).map(Some)
+        }
+
+        HdnSubcommand::Remove { packages, apply} => {
+            remove(packages, apply, &cli.home_dot_nix, attr, &config).map(Some)
+        }
+
+        HdnSubcommand::Disable { packages, apply } => {
+            update(UpdateNixMode::Disable, packages, apply, &cli.home_dot_nix, attr, &config).map(Some)
         }
 
-        HdnSubcommand::Remove { packages, show_trace} => {
-            remove(packages, show_trace)
+        HdnSubcommand::Enable { packages, apply } => {
+            update(UpdateNixMode::Enable, packages, apply, &cli.home_dot_nix, attr, &config).map(Some)
+        }
+
+        HdnSubcommand::Set { attrpath, value, expr, apply } => {
+            set(attrpath, value, expr, apply, &cli.home_dot_nix, &config).map(Some)
+        }
+
+        HdnSubcommand::Unset { attrpath, apply } => {
+            unset(attrpath, apply, &cli.home_dot_nix, &config).map(Some)
+        }
+
+        HdnSubcommand::Program { action } => match action {
+            ProgramAction::Enable { name, no_check, apply } => {
+                program_enable(name, true, no_check, apply, &cli.home_dot_nix, &config).map(Some)
+            }
+            ProgramAction::Disable { name, no_check, apply } => {
+                program_enable(name, false, no_check, apply, &cli.home_dot_nix, &config).map(Some)
+            }
+            ProgramAction::List { available, refresh } => {
+                program_list(available, refresh, &cli.home_dot_nix, &config).map(|()| None)
+            }
+        }
+
+        HdnSubcommand::Switch { show_trace } => {
+            switch(show_trace, &cli.home_dot_nix).map(Some)
+        }
+
+        HdnSubcommand::Search { query, refresh, from, limit } => {
+            search(query, refresh, from, limit, &cli.home_dot_nix, &config).map(|()| None)
+        }
+
+        HdnSubcommand::List { json, all, count } => {
+            list(json, all, count, &cli.home_dot_nix, attr).map(|()| None)
+        }
+
+        HdnSubcommand::Undo { count, apply } => {
+            undo(count, apply, &cli.home_dot_nix, &config).map(Some)
+        }
+
+        HdnSubcommand::History { action } => match action {
+            None => history().map(|()| None),
+            Some(HistoryAction::Show { id }) => history_show(id).map(|()| None)
         }
     };
 
@@ -283,7 +1443,9 @@ fn main() -> ExitCode {
             ExitCode::FAILURE
         }
         Ok(success) => {
-            println!("{success}");
+            if let Some(success) = success {
+                println!("{success}");
+            }
             ExitCode::SUCCESS
         }
     }
@@ -23,93 +23,84 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
  */
-use std::{fmt::Write};
-
 use rnix::{self, SyntaxKind, SyntaxNode};
 
+/// Finds the binding for an attribute path, which may be written with dots, as nested sets, or a mix of both,
+/// like `home.packages = ...;` or `home = { packages = ...; };`.
+///
+/// The binding returned is always part of the tree `configbase` is in, so it can be edited in place.
+/// Returns `None` if the attribute is only set through longer paths, like `programs.git` for `programs.git.enable = true;`.
 pub(crate) fn findattr(configbase: &SyntaxNode, name: &str) -> Option<SyntaxNode> {
-    let mut childvec: Vec<(String, String)> = Vec::new();
+    let qkey = name
+        .split('.')
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>();
     for child in configbase.children() {
         if child.kind() == SyntaxKind::NODE_ATTRPATH_VALUE {
-            let qkey = name
-                .split('.')
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>();
-            // Now we have to read all the indent values from the key
             for subchild in child.children() {
                 if subchild.kind() == SyntaxKind::NODE_ATTRPATH {
                     // We have a key, now we need to check if it's the one we're looking for
                     let key = getkey(&subchild);
                     if qkey == key {
-                        if child
-                            .children()
-                            .any(|x| x.kind() == SyntaxKind::NODE_ATTR_SET)
-                        {
-                            if let Some(x) = child.children().last() {
-                                if x.kind() == SyntaxKind::NODE_ATTR_SET {
-                                    for n in x.children() {
-                                        let i = n.children().count();
-                                        if let (Some(k), Some(v)) =
-                                            (n.children().nth(i - 2), n.last_child())
-                                        {
-                                            let f = n.to_string().find(&k.to_string()).unwrap()
-                                                + k.to_string().len();
-                                            childvec.push((
-                                                n.to_string()[0..f].to_string(),
-                                                v.to_string(),
-                                            ));
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            return Some(child);
-                        }
-                    } else if qkey.len() > key.len() {
-                        // We have a subkey, so we need to recurse
-                        if key == qkey[0..key.len()] {
-                            // We have a subkey, so we need to recurse
-                            let subkey = &qkey[key.len()..].join(".").to_string();
-                            if let Some(newbase) = getcfgbase(&child) {
-                                if let Some(subattr) = findattr(&newbase, subkey) {
-                                    return Some(subattr);
-                                }
+                        return Some(child);
+                    } else if qkey.len() > key.len() && key == qkey[0..key.len()] {
+                        // We have a subkey, so we need to recurse into the set
+                        let subkey = &qkey[key.len()..].join(".").to_string();
+                        if let Some(newbase) = getcfgbase(&child) {
+                            if let Some(subattr) = findattr(&newbase, subkey) {
+                                return Some(subattr);
                             }
                         }
-                    } else if qkey.len() < key.len() && qkey == key[0..qkey.len()] {
-                        if let Some(x) = child.last_child() {
-                            childvec.push((key[qkey.len()..].join("."), x.to_string()));
-                        }
                     }
                 }
             }
         }
     }
-    if childvec.is_empty() {
-        None
-    } else {
-        let s;
-        if childvec.len() == 1 {
-            s = format!("{{{} = {{ {} = {}; }}; }}", name, childvec[0].0, childvec[0].1);
-        } else {
-            let mut list = String::new();
-            for (k, v) in childvec.iter() {
-                let _ = writeln!(list, "  {} = {};", k, v);
+    None
+}
+
+/// Finds the innermost attribute set written for a prefix of the attribute path, like `home = { ... };` for `home.packages`,
+/// and the rest of the path, so a new binding can be added there instead of at the top level.
+pub(crate) fn findparentset(configbase: &SyntaxNode, name: &str) -> (SyntaxNode, String) {
+    let qkey = name.split('.').collect::<Vec<&str>>();
+    for i in (1..qkey.len()).rev() {
+        if let Some(binding) = findattr(configbase, &qkey[..i].join(".")) {
+            if let Some(set) = binding.last_child().filter(|x| x.kind() == SyntaxKind::NODE_ATTR_SET) {
+                return (set, qkey[i..].join("."));
             }
-            list = list.strip_suffix('\n').unwrap_or(&list).to_string();
-            s = format!("{{ {} = {{\n{}\n}}; }}", name, list);
         }
-        let ast = rnix::Root::parse(&s);
-        if let Some(x) = ast.syntax().children().next() {
-            if x.kind() == SyntaxKind::NODE_ATTR_SET {
-                if let Some(y) = x.children().next() {
-                    if y.kind() == SyntaxKind::NODE_ATTRPATH_VALUE {
-                        return Some(y);
-                    }
-                }
-            }
+    }
+    (configbase.clone(), name.to_string())
+}
+
+/// Finds the bindings that set the attribute only through longer paths, like `programs.git.enable = true;`
+/// or `programs = { git.enable = true; };` for `programs.git`, and gives their full paths.
+pub(crate) fn findlonger(configbase: &SyntaxNode, name: &str) -> Vec<String> {
+    let qkey = name.split('.').map(|s| s.to_string()).collect::<Vec<String>>();
+    let mut out = vec![];
+    findlonger_aux(configbase, &[], &qkey, &mut out);
+    out
+}
+
+fn findlonger_aux(node: &SyntaxNode, prefix: &[String], qkey: &[String], out: &mut Vec<String>) {
+    for child in node.children() {
+        if child.kind() != SyntaxKind::NODE_ATTRPATH_VALUE {
+            continue;
+        }
+        let Some(attrpath) = child.first_child().filter(|x| x.kind() == SyntaxKind::NODE_ATTRPATH) else {
+            continue;
+        };
+        let mut key = prefix.to_vec();
+        key.extend(getkey(&attrpath));
+        let n = key.len().min(qkey.len());
+        if key[..n] != qkey[..n] {
+            continue;
+        }
+        if key.len() > qkey.len() {
+            out.push(key.join("."));
+        } else if let Some(set) = child.last_child().filter(|x| x.kind() == SyntaxKind::NODE_ATTR_SET) {
+            findlonger_aux(&set, &key, qkey, out);
         }
-        None
     }
 }
 
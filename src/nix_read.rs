@@ -23,10 +23,16 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
  */
-use crate::nix_parse::{findattr, getcfgbase};
+use crate::nix_parse::{findattr, getcfgbase, getkey};
 use rnix::{SyntaxKind, SyntaxNode};
+use std::ops::Range;
 use thiserror::Error;
 
+/// Marks the first line of a list element that hdn disable turned into a comment
+pub(crate) const DISABLED: &str = "# hdn-disabled: ";
+/// Marks the following lines of a disabled element that spanned several lines
+pub(crate) const DISABLED_CONTINUED: &str = "# hdn-disabled| ";
+
 #[derive(Error, Debug)]
 pub(crate) enum ReadError {
     #[error("Error while parsing")]
@@ -37,7 +43,96 @@ pub(crate) enum ReadError {
     ArrayError,
 }
 
+/// Functions that make a list conditional, like `lib.optionals stdenv.isLinux [ ... ]`
+const CONDITIONALS: [&str; 5] = ["lib.optionals", "optionals", "lib.lists.optionals", "lib.mkIf", "mkIf"];
+
+/// One of the lists that the value of a binding is made of
+pub(crate) struct ArrList {
+    pub(crate) node: SyntaxNode,
+    /// The condition the list is under, if it is in `lib.optionals` or `lib.mkIf`
+    pub(crate) condition: Option<String>,
+    /// The namespaces of the `with` expressions around the list, outermost first
+    pub(crate) scopes: Vec<String>,
+}
+
+/// Finds the lists in the value of a binding, which may be a single list or several joined with `++`,
+/// each possibly inside `with`, parentheses, `lib.optionals <condition>` or `lib.mkIf <condition>`.
+pub(crate) fn getlists(binding: &SyntaxNode) -> Vec<ArrList> {
+    let mut out = vec![];
+    if let Some(value) = binding.last_child() {
+        getlists_aux(&value, None, &[], &mut out);
+    }
+    out
+}
+
+fn getlists_aux(node: &SyntaxNode, condition: Option<String>, scopes: &[String], out: &mut Vec<ArrList>) {
+    match node.kind() {
+        SyntaxKind::NODE_LIST => out.push(ArrList {
+            node: node.clone(),
+            condition,
+            scopes: scopes.to_vec(),
+        }),
+        SyntaxKind::NODE_WITH => {
+            if let (Some(namespace), Some(body)) = (node.first_child(), node.last_child()) {
+                let mut scopes = scopes.to_vec();
+                scopes.push(namespace.to_string());
+                getlists_aux(&body, condition, &scopes, out);
+            }
+        }
+        SyntaxKind::NODE_PAREN => {
+            if let Some(inner) = node.first_child() {
+                getlists_aux(&inner, condition, scopes, out);
+            }
+        }
+        SyntaxKind::NODE_BIN_OP => {
+            let concat = node.children_with_tokens().any(|x| x.kind() == SyntaxKind::TOKEN_CONCAT);
+            if concat {
+                for operand in node.children() {
+                    getlists_aux(&operand, condition.clone(), scopes, out);
+                }
+            }
+        }
+        SyntaxKind::NODE_APPLY => {
+            // `f a b` is parsed as `(f a) b`
+            let mut args = vec![];
+            let mut function = node.clone();
+            while function.kind() == SyntaxKind::NODE_APPLY {
+                match (function.first_child(), function.last_child()) {
+                    (Some(f), Some(arg)) => {
+                        args.push(arg);
+                        function = f;
+                    }
+                    _ => return,
+                }
+            }
+            args.reverse();
+            if let [cond, list] = args.as_slice() {
+                if CONDITIONALS.contains(&function.to_string().as_str()) {
+                    let cond = match condition {
+                        Some(outer) => format!("{} && {}", outer, cond),
+                        None => cond.to_string(),
+                    };
+                    getlists_aux(list, Some(cond), scopes, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Picks the list to add to: the first one that isn't under a condition.
+pub(crate) fn getunconditional(lists: &[ArrList]) -> Option<&ArrList> {
+    lists.iter().find(|x| x.condition.is_none())
+}
+
 pub(crate) fn getarrvals(f: &str, query: &str) -> Result<Vec<String>, ReadError> {
+    let entries = getarrentries(f, query)?;
+    Ok(entries.into_iter().map(|(value, _)| value).collect())
+}
+
+/// Gets every element of the list, from all the lists it is made of,
+/// along with the condition each one is under.
+pub(crate) fn getarrentries(f: &str, query: &str) -> Result<Vec<(String, Option<String>)>, ReadError> {
     let ast = rnix::Root::parse(f);
     let configbase = match getcfgbase(&ast.syntax()) {
         Some(x) => x,
@@ -46,7 +141,39 @@ pub(crate) fn getarrvals(f: &str, query: &str) -> Result<Vec<String>, ReadError>
         }
     };
     let output = match findattr(&configbase, query) {
-        Some(x) => match getarrvals_aux(&x) {
+        Some(x) => match getarrentries_aux(&x) {
+            Some(y) => y,
+            None => return Err(ReadError::ArrayError),
+        },
+        None => return Err(ReadError::NoAttr),
+    };
+    Ok(output)
+}
+
+fn getarrentries_aux(node: &SyntaxNode) -> Option<Vec<(String, Option<String>)>> {
+    let lists = getlists(node);
+    if lists.is_empty() {
+        return None;
+    }
+    let mut out = vec![];
+    for list in lists {
+        for elem in list.node.children() {
+            out.push((elem.to_string(), list.condition.clone()));
+        }
+    }
+    Some(out)
+}
+
+/// Gets the elements of the list that hdn disable turned into comments, as they were written before,
+/// along with the condition of the list each one is in.
+pub(crate) fn getdisabled(f: &str, query: &str) -> Result<Vec<(String, Option<String>)>, ReadError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(ReadError::ParseError),
+    };
+    let output = match findattr(&configbase, query) {
+        Some(x) => match getdisabled_aux(&x) {
             Some(y) => y,
             None => return Err(ReadError::ArrayError),
         },
@@ -55,18 +182,195 @@ pub(crate) fn getarrvals(f: &str, query: &str) -> Result<Vec<String>, ReadError>
     Ok(output)
 }
 
-fn getarrvals_aux(node: &SyntaxNode) -> Option<Vec<String>> {
+fn getdisabled_aux(node: &SyntaxNode) -> Option<Vec<(String, Option<String>)>> {
+    let lists = getlists(node);
+    if lists.is_empty() {
+        return None;
+    }
+    let mut out = vec![];
+    for list in lists {
+        for (_, text) in disabledentries(&list.node) {
+            out.push((disabledelement(&text), list.condition.clone()));
+        }
+    }
+    Some(out)
+}
+
+/// Finds the disabled elements in a list.
+///
+/// Returns the children of the list that make up each one, and its text with the comment markers taken off,
+/// which includes any comment that was after the element on the same line.
+pub(crate) fn disabledentries(list: &SyntaxNode) -> Vec<(Range<usize>, String)> {
+    let children: Vec<_> = list.children_with_tokens().collect();
+    let comment = |i: usize, marker: &str| -> Option<String> {
+        let token = children.get(i)?.as_token()?;
+        if token.kind() != SyntaxKind::TOKEN_COMMENT {
+            return None;
+        }
+        token.text().trim_end_matches('\r').strip_prefix(marker).map(str::to_string)
+    };
+
+    let mut out = vec![];
+    let mut i = 0;
+    while i < children.len() {
+        let Some(mut text) = comment(i, DISABLED) else {
+            i += 1;
+            continue;
+        };
+        let indent = match i.checked_sub(1).and_then(|j| children[j].as_token()) {
+            Some(t) => t.text().rsplit('\n').next().unwrap_or("").to_string(),
+            None => String::new(),
+        };
+        let mut end = i + 1;
+        while children.get(end).is_some_and(|x| x.to_string().trim().is_empty() && x.to_string().matches('\n').count() == 1) {
+            let Some(line) = comment(end + 1, DISABLED_CONTINUED) else {
+                break;
+            };
+            text.push('\n');
+            text.push_str(&indent);
+            text.push_str(&line);
+            end += 2;
+        }
+        out.push((i..end, text));
+        i = end;
+    }
+    out
+}
+
+/// Gets the element from the text of a disabled entry, without the comment that may follow it.
+pub(crate) fn disabledelement(text: &str) -> String {
+    match rnix::Root::parse(text).syntax().first_child() {
+        Some(x) => x.to_string(),
+        None => text.to_string(),
+    }
+}
+
+/// Gets the namespaces of the `with` expressions around the list that is added to, outermost first,
+/// so that `with pkgs; [ ... ]` gives `["pkgs"]`.
+pub(crate) fn getwithscopes(f: &str, query: &str) -> Result<Vec<String>, ReadError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(ReadError::ParseError),
+    };
+    let output = match findattr(&configbase, query) {
+        Some(x) => getwithscopes_aux(&x),
+        None => return Err(ReadError::NoAttr),
+    };
+    Ok(output)
+}
+
+fn getwithscopes_aux(node: &SyntaxNode) -> Vec<String> {
+    let lists = getlists(node);
+    match getunconditional(&lists).or(lists.first()) {
+        Some(list) => list.scopes.clone(),
+        None => vec![],
+    }
+}
+
+/// Gets the value the attribute is bound to, as it is written.
+pub(crate) fn getval(f: &str, query: &str) -> Result<String, ReadError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(ReadError::ParseError),
+    };
+    match findattr(&configbase, query).and_then(|x| x.last_child()) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(ReadError::NoAttr),
+    }
+}
+
+/// Whether the attribute is set, either directly or through longer paths, like `programs.git.enable` for `programs.git`.
+pub(crate) fn hasattr(f: &str, query: &str) -> bool {
+    let ast = rnix::Root::parse(f);
+    let Some(configbase) = getcfgbase(&ast.syntax()) else {
+        return false;
+    };
+    if findattr(&configbase, query).is_some() {
+        return true;
+    }
+    let qkey: Vec<&str> = query.split('.').collect();
+    let mut bindings = vec![];
+    getbindings_aux(&configbase, &[], &mut bindings);
+    bindings.iter().any(|(key, _)| key.len() > qkey.len() && key[..qkey.len()] == qkey[..])
+}
+
+/// Flattens the bindings of the top-level attribute set,
+/// so that `a.b = { c = 1; };` gives the key `["a", "b", "c"]` and the value `1`.
+pub(crate) fn getbindings(f: &str) -> Result<Vec<(Vec<String>, String)>, ReadError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(ReadError::ParseError),
+    };
+    let mut out = vec![];
+    getbindings_aux(&configbase, &[], &mut out);
+    Ok(out)
+}
+
+fn getbindings_aux(node: &SyntaxNode, prefix: &[String], out: &mut Vec<(Vec<String>, String)>) {
     for child in node.children() {
-        if child.kind() == rnix::SyntaxKind::NODE_WITH {
-            return getarrvals_aux(&child);
+        if child.kind() != SyntaxKind::NODE_ATTRPATH_VALUE {
+            continue;
         }
-        if child.kind() == SyntaxKind::NODE_LIST {
-            let mut out = vec![];
-            for elem in child.children() {
-                out.push(elem.to_string());
+        if let (Some(attrpath), Some(value)) = (child.first_child(), child.last_child()) {
+            let mut key = prefix.to_vec();
+            key.extend(getkey(&attrpath).iter().map(|k| k.trim_matches('"').to_string()));
+            if value.kind() == SyntaxKind::NODE_ATTR_SET {
+                getbindings_aux(&value, &key, out);
+            } else {
+                out.push((key, value.to_string()));
             }
-            return Some(out);
         }
     }
-    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(package: &str, condition: Option<&str>) -> (String, Option<String>) {
+        (package.to_string(), condition.map(str::to_string))
+    }
+
+    #[test]
+    fn getarrentries_concat() {
+        let f = "{\n  home.packages = [ pkgs.git ] ++ lib.optionals stdenv.isLinux [ pkgs.strace ];\n}\n";
+        assert_eq!(
+            getarrentries(f, "home.packages").unwrap(),
+            vec![entry("pkgs.git", None), entry("pkgs.strace", Some("stdenv.isLinux"))]
+        );
+    }
+
+    #[test]
+    fn getarrentries_nested_optionals() {
+        let f = "{\n  home.packages = lib.optionals stdenv.isLinux ([ pkgs.strace ] ++ lib.optionals cond [ pkgs.gdb ]);\n}\n";
+        assert_eq!(
+            getarrentries(f, "home.packages").unwrap(),
+            vec![entry("pkgs.strace", Some("stdenv.isLinux")), entry("pkgs.gdb", Some("stdenv.isLinux && cond"))]
+        );
+    }
+
+    #[test]
+    fn getarrentries_mkif() {
+        let f = "{\n  home.packages = lib.mkIf cond [ pkgs.firefox ];\n}\n";
+        assert_eq!(getarrentries(f, "home.packages").unwrap(), vec![entry("pkgs.firefox", Some("cond"))]);
+    }
+
+    #[test]
+    fn getarrentries_nested_with() {
+        let f = "{\n  home.packages = with pkgs; [ git ] ++ lib.optionals stdenv.isLinux (with pkgs.linuxPackages; [ perf ]);\n}\n";
+        assert_eq!(
+            getarrentries(f, "home.packages").unwrap(),
+            vec![entry("git", None), entry("perf", Some("stdenv.isLinux"))]
+        );
+        assert_eq!(getwithscopes(f, "home.packages").unwrap(), vec!["pkgs"]);
+        let lists = {
+            let ast = rnix::Root::parse(f);
+            let configbase = getcfgbase(&ast.syntax()).unwrap();
+            getlists(&findattr(&configbase, "home.packages").unwrap())
+        };
+        assert_eq!(lists[1].scopes, vec!["pkgs", "pkgs.linuxPackages"]);
+    }
 }
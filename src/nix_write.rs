@@ -23,7 +23,8 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
  */
-use crate::nix_parse::{findattr, getcfgbase, getkey};
+use crate::nix_parse::{findattr, findlonger, findparentset, getcfgbase, getkey};
+use crate::nix_read::{disabledelement, disabledentries, getlists, getunconditional, DISABLED, DISABLED_CONTINUED};
 use rnix::{self, SyntaxKind, SyntaxNode};
 use thiserror::Error;
 
@@ -35,6 +36,12 @@ pub(crate) enum WriteError {
     NoAttr,
     #[error("Error with array.")]
     ArrayError,
+    #[error("Error with value.")]
+    ValueError,
+    #[error("Every array is under a condition.")]
+    NoUnconditionalArray,
+    #[error("No array is under the condition {0}.")]
+    NoConditionalArray(String),
 }
 
 fn addvalue(configbase: &SyntaxNode, query: &str, val: &str) -> SyntaxNode {
@@ -67,7 +74,8 @@ fn addvalue(configbase: &SyntaxNode, query: &str, val: &str) -> SyntaxNode {
             }
         }
     }
-    let input = rnix::Root::parse(format!("\n  {} = {};", &query, &val).as_str()).syntax();
+    let input = format!("{}{}{} = {};", newline(configbase), childindent(configbase), &query, &val);
+    let input = rnix::Root::parse(&input).syntax();
     let input = input.green().clone();
     if index == 0 {
         index += 1;
@@ -104,78 +112,272 @@ fn matchval(configbase: &SyntaxNode, query: &str, acc: usize) -> Option<SyntaxNo
     }
 }
 
-pub(crate) fn addtoarr(f: &str, query: &str, items: Vec<String>) -> Result<String, WriteError> {
+pub(crate) fn addtoarr(f: &str, query: &str, items: Vec<String>, sort: bool, width: usize) -> Result<String, WriteError> {
     let ast = rnix::Root::parse(f);
     let configbase = match getcfgbase(&ast.syntax()) {
         Some(x) => x,
         None => return Err(WriteError::ParseError),
     };
     let outnode = match findattr(&configbase, query) {
-        Some(x) => match addtoarr_aux(&x, items) {
+        Some(x) => match addtoarr_aux(&x, None, items, sort, width) {
             Some(x) => x,
+            None if !getlists(&x).is_empty() => return Err(WriteError::NoUnconditionalArray),
             None => return Err(WriteError::ArrayError),
         },
-        // If no arrtibute is found, create a new one
+        // If no arrtibute is found, create a new one, in the set for its parent if there is one
         None => {
-            let newval = addvalue(&configbase, query, "[\n  ]");
-            return addtoarr(&newval.to_string(), query, items);
+            let (set, rest) = findparentset(&configbase, query);
+            // Close the new list at the indentation of the binding it is in
+            let newlist = format!("[{}{}]", newline(&set), childindent(&set));
+            let newval = addvalue(&set, &rest, &newlist);
+            return addtoarr(&newval.to_string(), query, items, sort, width);
         }
     };
     Ok(outnode.to_string())
 }
 
-fn addtoarr_aux(node: &SyntaxNode, items: Vec<String>) -> Option<SyntaxNode> {
-    for child in node.children() {
-        if child.kind() == rnix::SyntaxKind::NODE_WITH {
-            return addtoarr_aux(&child, items);
-        }
-        if child.kind() == SyntaxKind::NODE_LIST {
-            let mut green = child.green().into_owned();
-
-            for elem in items {
-                let mut i = 0;
-                for c in green.children() {
-                    if c.to_string() == "]" {
-                        if green.children().collect::<Vec<_>>()[i - 1]
-                            .as_token()
-                            .unwrap()
-                            .to_string()
-                            .contains('\n')
-                        {
-                            i -= 1;
-                        }
-                        green = green.insert_child(
-                            i,
-                            rnix::NodeOrToken::Node(
-                                rnix::Root::parse(&format!("\n{}{}", " ".repeat(4), elem))
-                                    .syntax()
-                                    .green()
-                                    .into_owned(),
-                            ),
-                        );
-                        break;
-                    }
-                    i += 1;
+/// Adds the items to the list under `condition`, like `stdenv.isLinux` for `lib.optionals stdenv.isLinux [ ... ]`.
+/// Unlike addtoarr, this doesn't create the list if there isn't one.
+pub(crate) fn addtocond(f: &str, query: &str, items: Vec<String>, condition: &str, sort: bool, width: usize) -> Result<String, WriteError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(WriteError::ParseError),
+    };
+    let outnode = match findattr(&configbase, query) {
+        Some(x) => match addtoarr_aux(&x, Some(condition), items, sort, width) {
+            Some(x) => x,
+            None => return Err(WriteError::NoConditionalArray(condition.to_string())),
+        },
+        None => return Err(WriteError::NoAttr),
+    };
+    Ok(outnode.to_string())
+}
+
+fn addtoarr_aux(node: &SyntaxNode, condition: Option<&str>, items: Vec<String>, sort: bool, width: usize) -> Option<SyntaxNode> {
+    // Packages are added unconditionally, unless a condition is given
+    let lists = getlists(node);
+    let list = match condition {
+        Some(condition) => lists.iter().find(|x| x.condition.as_deref() == Some(condition))?,
+        None => getunconditional(&lists)?,
+    };
+    let text = addtolist(&list.node, items, sort, width)?;
+    Some(replacelists(vec![(list.node.clone(), text)]))
+}
+
+/// Adds the items to the list, at the end, or at their sorted positions if `sort` is set and the list is sorted.
+///
+/// A list written on one line, like `[ pkgs.git pkgs.curl ]`, stays on one line
+/// unless that line would become longer than `width`, in which case it is written one element per line.
+fn addtolist(child: &SyntaxNode, items: Vec<String>, sort: bool, width: usize) -> Option<String> {
+    let mut green = child.green().into_owned();
+    let singleline = !child.text().contains_char('\n');
+    let prefix = format!("{}{}", newline(child), childindent(child));
+    // Only keep the list sorted if it already is, so hand-ordered lists are left alone
+    let sort = sort && issorted(child);
+
+    for elem in items {
+        let mut i = 0;
+        for c in green.children() {
+            if c.to_string() == "]" {
+                let children = green.children().collect::<Vec<_>>();
+                if singleline {
+                    let list = SyntaxNode::new_root(green.clone());
+                    let (index, text) = singlelineinsert(&list, i, sort, &elem);
+                    i = index;
+                    green = green.insert_child(
+                        i,
+                        rnix::NodeOrToken::Node(
+                            rnix::Root::parse(&text).syntax().green().into_owned(),
+                        ),
+                    );
+                    // Parse the list again, so the spaces around the new element are tokens of the list
+                    // that the next element can be placed against
+                    green = rnix::Root::parse(&green.to_string()).syntax().first_child()?.green().into_owned();
+                    break;
+                }
+                // Insert before the line break in front of `]`, or right before it if it shares the last element's line
+                if i > 0 && children[i - 1].as_token().is_some_and(|t| t.text().contains('\n')) {
+                    i -= 1;
                 }
+                if sort {
+                    i = sortedindex(&SyntaxNode::new_root(green.clone()), &elem).unwrap_or(i);
+                }
+                green = green.insert_child(
+                    i,
+                    rnix::NodeOrToken::Node(
+                        rnix::Root::parse(&format!("{}{}", prefix, elem))
+                            .syntax()
+                            .green()
+                            .into_owned(),
+                    ),
+                );
+                break;
             }
+            i += 1;
+        }
+    }
 
-            let index = match node.green().children().position(|x| match x.into_node() {
-                Some(x) => x.to_owned() == child.green().into_owned(),
-                None => false,
-            }) {
-                Some(x) => x,
-                None => return None,
-            };
+    if singleline && linewidth(child, &green.to_string()) > width {
+        let text = multiline(child, &SyntaxNode::new_root(green.clone()));
+        green = rnix::Root::parse(&text).syntax().first_child()?.green().into_owned();
+    }
+    Some(SyntaxNode::new_root(green).to_string())
+}
+
+/// Replaces each list with its new text, and parses the result.
+fn replacelists(edits: Vec<(SyntaxNode, String)>) -> SyntaxNode {
+    let mut edits = edits;
+    let root = match edits.first() {
+        Some((x, _)) => x.ancestors().last().unwrap_or(x.clone()),
+        None => return rnix::Root::parse("").syntax(),
+    };
+    let mut out = root.to_string();
+    // Replace from the end, so the earlier ranges stay valid
+    edits.sort_by_key(|(x, _)| std::cmp::Reverse(x.text_range().start()));
+    for (list, text) in edits {
+        let range = list.text_range();
+        out.replace_range(usize::from(range.start())..usize::from(range.end()), &text);
+    }
+    rnix::Root::parse(&out).syntax()
+}
 
-            let replace = node
-                .green()
-                .replace_child(index, rnix::NodeOrToken::Node(green));
-            let out = node.replace_with(replace);
-            let output = rnix::Root::parse(&out.to_string()).syntax();
-            return Some(output);
+/// Finds where to insert `elem` into a list written on one line, whose closing bracket is at `close`,
+/// and the text to insert there, with the space that separates it from its neighbours.
+fn singlelineinsert(list: &SyntaxNode, close: usize, sort: bool, elem: &str) -> (usize, String) {
+    let children: Vec<_> = list.children_with_tokens().collect();
+    let iswhitespace = |i: usize| children[i].kind() == SyntaxKind::TOKEN_WHITESPACE;
+    let next = if sort { sortedindex(list, elem) } else { None };
+    match next {
+        // Insert after the space before the next element, so the space before it separates the two
+        Some(next) if iswhitespace(next - 1) => (next - 1, format!(" {}", elem)),
+        Some(next) => (next, format!("{} ", elem)),
+        None if iswhitespace(close - 1) => (close - 1, format!(" {}", elem)),
+        // `[]` becomes `[ elem ]`, and `[a]` becomes `[a elem]`
+        None if list.children().next().is_none() => (close, format!(" {} ", elem)),
+        None => (close, format!(" {}", elem)),
+    }
+}
+
+/// The width of the line the list is on, if the list were replaced with `text`.
+fn linewidth(list: &SyntaxNode, text: &str) -> usize {
+    let root = list.ancestors().last().unwrap_or(list.clone()).to_string();
+    let start = usize::from(list.text_range().start());
+    let end = usize::from(list.text_range().end());
+    let linestart = root[..start].rfind('\n').map_or(0, |i| i + 1);
+    let lineend = root[end..].find('\n').map_or(root.len(), |i| end + i);
+    root[linestart..start].chars().count() + text.chars().count() + root[end..lineend].trim_end().chars().count()
+}
+
+/// Writes a list that is on one line with one element per line,
+/// indented one level deeper than the line the list starts on.
+fn multiline(original: &SyntaxNode, list: &SyntaxNode) -> String {
+    let root = original.ancestors().last().unwrap_or(original.clone()).to_string();
+    let newline = newline(original);
+    let indent = lineindent(original);
+    let mut out = String::from("[");
+    for child in list.children_with_tokens() {
+        match child.kind() {
+            SyntaxKind::TOKEN_L_BRACK | SyntaxKind::TOKEN_R_BRACK | SyntaxKind::TOKEN_WHITESPACE => {}
+            _ => out.push_str(&format!("{}{}{}{}", newline, indent, indentunit(&root), child.to_string().trim())),
+        }
+    }
+    out.push_str(&format!("{}{}]", newline, indent));
+    out
+}
+
+/// The line ending the file uses.
+fn newline(node: &SyntaxNode) -> &'static str {
+    let root = node.ancestors().last().unwrap_or(node.clone());
+    if root.text().contains_char('\r') {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// The indentation for a new child of a list or attribute set: that of the existing children,
+/// or if there are none, one level deeper than the line the node starts on.
+fn childindent(node: &SyntaxNode) -> String {
+    for child in node.children() {
+        if let Some(rnix::NodeOrToken::Token(x)) = child.prev_sibling_or_token() {
+            if let Some((_, indent)) = x.text().rsplit_once('\n') {
+                return indent.to_string();
+            }
+        }
+    }
+    let root = node.ancestors().last().unwrap_or(node.clone()).to_string();
+    lineindent(node) + &indentunit(&root)
+}
+
+/// The indentation of the line the node starts on.
+fn lineindent(node: &SyntaxNode) -> String {
+    let root = node.ancestors().last().unwrap_or(node.clone()).to_string();
+    let start = usize::from(node.text_range().start());
+    let linestart = root[..start].rfind('\n').map_or(0, |i| i + 1);
+    root[linestart..]
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect()
+}
+
+/// One level of indentation: a tab if the file indents with tabs,
+/// otherwise the smallest indentation of any line, which defaults to two spaces.
+fn indentunit(f: &str) -> String {
+    let indents = f
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .filter(|indent| !indent.is_empty());
+    let mut unit: Option<&str> = None;
+    for indent in indents {
+        if indent.starts_with('\t') {
+            return "\t".to_string();
+        }
+        if unit.is_none_or(|u| indent.len() < u.len()) {
+            unit = Some(indent);
         }
     }
-    None
+    unit.unwrap_or("  ").to_string()
+}
+
+/// The key list elements are sorted by, ignoring case and the `pkgs.` prefix.
+fn sortkey(elem: &str) -> String {
+    let elem = elem.trim();
+    elem.strip_prefix("pkgs.").unwrap_or(elem).to_lowercase()
+}
+
+fn issorted(list: &SyntaxNode) -> bool {
+    let keys: Vec<String> = list
+        .children()
+        .map(|x| sortkey(&x.to_string()))
+        .collect();
+    keys.windows(2).all(|w| w[0] <= w[1])
+}
+
+/// Finds where to insert `elem` to keep the list sorted, or `None` if it goes last.
+///
+/// The element is inserted before the first element that sorts after it, and before the comments on the lines
+/// above that element, which belong to it. A comment on the same line as the previous element stays with that one.
+fn sortedindex(list: &SyntaxNode, elem: &str) -> Option<usize> {
+    let children: Vec<_> = list.children_with_tokens().collect();
+    let key = sortkey(elem);
+    let next = children.iter().position(|x| match x.as_node() {
+        Some(y) => sortkey(&y.to_string()) > key,
+        None => false,
+    })?;
+    // Everything between the previous element (or the opening bracket) and the first line break
+    // is the previous element's trailing comment
+    let previous = children[..next]
+        .iter()
+        .rposition(|x| x.as_node().is_some() || x.kind() == SyntaxKind::TOKEN_L_BRACK)?;
+    let linebreak = children[previous + 1..next]
+        .iter()
+        .position(|x| x.as_token().is_some_and(|t| t.text().contains('\n')));
+    Some(match linebreak {
+        Some(j) => previous + 1 + j,
+        None => next,
+    })
 }
 
 pub(crate) fn rmarr(f: &str, query: &str, items: Vec<String>) -> Result<String, WriteError> {
@@ -195,53 +397,673 @@ pub(crate) fn rmarr(f: &str, query: &str, items: Vec<String>) -> Result<String,
 }
 
 fn rmarr_aux(node: &SyntaxNode, items: Vec<String>) -> Option<SyntaxNode> {
-    for child in node.children() {
-        if child.kind() == rnix::SyntaxKind::NODE_WITH {
-            return rmarr_aux(&child, items);
-        }
-        if child.kind() == SyntaxKind::NODE_LIST {
-            let green = child.green().into_owned();
-            let mut idx = vec![];
-            for elem in green.children() {
-                if elem.as_node().is_some() && items.contains(&elem.to_string()) {
-                    let index = match green.children().position(|x| match x.into_node() {
-                        Some(x) => {
-                            if let Some(y) = elem.as_node() {
-                                x.eq(y)
-                            } else {
-                                false
-                            }
-                        }
-                        None => false,
-                    }) {
-                        Some(x) => x,
-                        None => return None,
-                    };
-                    idx.push(index)
-                }
-            }
-            let mut acc = 0;
-            let mut replace = green;
-
-            for i in idx {
-                replace = replace.remove_child(i - acc);
-                let mut v = vec![];
-                for c in replace.children() {
-                    v.push(c);
-                }
-                if let Some(x) = v.get(i - acc - 1).unwrap().as_token() {
-                    if x.to_string().contains('\n') {
-                        replace = replace.remove_child(i - acc - 1);
-                        acc += 1;
-                    }
-                }
-                acc += 1;
+    // The element may be in any of the lists
+    let lists = getlists(node);
+    if lists.is_empty() {
+        return None;
+    }
+    let mut edits = vec![];
+    for list in lists {
+        let text = rmlist(&list.node, &items)?;
+        edits.push((list.node, text));
+    }
+    Some(replacelists(edits))
+}
+
+fn rmlist(child: &SyntaxNode, items: &[String]) -> Option<String> {
+    // Remove one element at a time, finding the range of the next one in what's left,
+    // since the ranges of neighbouring elements like those in `[a b]` share the space between them
+    let mut list = SyntaxNode::new_root(child.green().into_owned());
+    while let Some(elem) = list.children().find(|x| items.contains(&x.to_string())) {
+        let mut replace = list.green().into_owned();
+        for i in elementrange(&list, elem.index()).rev() {
+            replace = replace.remove_child(i);
+        }
+        list = SyntaxNode::new_root(replace);
+    }
+    Some(list.to_string())
+}
+
+/// Finds the children of the list that belong to the element at `index`, and are removed along with it.
+///
+/// These are the element itself, a comment after it on the same line, and the whitespace before it
+/// (or, for the first element of a list like `[a b]`, after it).
+/// Comments on the lines directly above the element belong to it too, unless the element is directly followed
+/// by another element: then the comments are taken to be a heading for a group of elements, and are kept.
+/// A blank line between a comment and the element means the comment doesn't belong to it.
+fn elementrange(list: &SyntaxNode, index: usize) -> std::ops::Range<usize> {
+    let children: Vec<_> = list.children_with_tokens().collect();
+    let kind = |i: usize| children.get(i).map(|x| x.kind());
+    let newlines = |i: usize| match children.get(i).and_then(|x| x.as_token()) {
+        Some(t) if t.kind() == SyntaxKind::TOKEN_WHITESPACE => Some(t.text().matches('\n').count()),
+        _ => None,
+    };
+
+    let mut end = index + 1;
+    if newlines(end) == Some(0) && kind(end + 1) == Some(SyntaxKind::TOKEN_COMMENT) {
+        end += 2;
+    } else if kind(end) == Some(SyntaxKind::TOKEN_COMMENT) {
+        end += 1;
+    }
+
+    let heading = newlines(end) == Some(1) && children.get(end + 1).is_some_and(|x| x.as_node().is_some());
+    let mut start = index;
+    while !heading
+        && start >= 3
+        && newlines(start - 1) == Some(1)
+        && kind(start - 2) == Some(SyntaxKind::TOKEN_COMMENT)
+        && newlines(start - 3).is_some_and(|n| n > 0)
+    {
+        start -= 2;
+    }
+
+    if start > 0 && newlines(start - 1).is_some() {
+        start -= 1;
+    } else if newlines(end).is_some() {
+        end += 1;
+    }
+    start..end
+}
+
+/// Turns the elements into `# hdn-disabled: ` comments, which enablearr turns back.
+pub(crate) fn disablearr(f: &str, query: &str, items: Vec<String>) -> Result<String, WriteError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(WriteError::ParseError),
+    };
+    let outnode = match findattr(&configbase, query) {
+        Some(x) => match disablearr_aux(&x, items) {
+            Some(x) => x,
+            None => return Err(WriteError::ArrayError),
+        },
+        None => return Err(WriteError::NoAttr),
+    };
+    Ok(outnode.to_string())
+}
+
+fn disablearr_aux(node: &SyntaxNode, items: Vec<String>) -> Option<SyntaxNode> {
+    let lists = getlists(node);
+    if lists.is_empty() {
+        return None;
+    }
+    let mut edits = vec![];
+    for list in lists {
+        let text = disablelist(&list.node, &items)?;
+        edits.push((list.node, text));
+    }
+    Some(replacelists(edits))
+}
+
+fn disablelist(child: &SyntaxNode, items: &[String]) -> Option<String> {
+    if !child.children().any(|elem| items.contains(&elem.to_string())) {
+        return Some(child.to_string());
+    }
+    // A comment runs to the end of the line, so a list on one line has to be split first
+    let mut list = if child.text().contains_char('\n') {
+        SyntaxNode::new_root(child.green().into_owned())
+    } else {
+        rnix::Root::parse(&multiline(child, child)).syntax().first_child()?
+    };
+    let newline = newline(child);
+    let inner = childindent(child);
+    let outer = lineindent(child);
+
+    // Disable one element at a time, parsing the list again after each change, since moving an element
+    // onto its own line changes the children around it
+    while let Some(elem) = list.children().find(|x| items.contains(&x.to_string())) {
+        let text = ownline(&list, elem.index(), newline, &inner, &outer);
+        list = rnix::Root::parse(&text).syntax().first_child()?;
+        let elem = list.children().find(|x| items.contains(&x.to_string()))?;
+        let children: Vec<_> = list.children_with_tokens().collect();
+
+        // Take the comment after the element along, so it comes back with it
+        let start = elem.index();
+        let end = trailingcomment(&children, start);
+
+        let indent = match start.checked_sub(1).and_then(|i| children[i].as_token()) {
+            Some(t) => t.text().rsplit('\n').next().unwrap_or("").to_string(),
+            None => String::new(),
+        };
+        let text: String = children[start..end].iter().map(|x| x.to_string()).collect();
+        let mut lines = text.split('\n').map(|line| line.trim_end_matches('\r'));
+        let mut comment = format!("{}{}", DISABLED, lines.next().unwrap_or(""));
+        for line in lines {
+            let line = line.strip_prefix(indent.as_str()).unwrap_or(line.trim_start());
+            comment.push_str(&format!("{}{}{}{}", newline, indent, DISABLED_CONTINUED, line));
+        }
+
+        let before: String = children[..start].iter().map(|x| x.to_string()).collect();
+        let after: String = children[end..].iter().map(|x| x.to_string()).collect();
+        list = rnix::Root::parse(&format!("{before}{comment}{after}")).syntax().first_child()?;
+    }
+    Some(list.to_string())
+}
+
+/// Finds the end of the element at `start` along with the comment after it on the same line, if there is one.
+fn trailingcomment(children: &[rnix::NodeOrToken<SyntaxNode, rnix::SyntaxToken>], start: usize) -> usize {
+    let istoken = |i: usize, kind: SyntaxKind| children.get(i).is_some_and(|x| x.kind() == kind);
+    let mut end = start + 1;
+    if istoken(end, SyntaxKind::TOKEN_WHITESPACE)
+        && !children[end].to_string().contains('\n')
+        && istoken(end + 1, SyntaxKind::TOKEN_COMMENT)
+    {
+        end += 2;
+    } else if istoken(end, SyntaxKind::TOKEN_COMMENT) {
+        end += 1;
+    }
+    end
+}
+
+/// Puts the element at `index` on a line of its own, keeping only the comment after it,
+/// so that turning the line into a comment doesn't take a neighbour or the `]` along.
+/// `inner` is the indentation of the elements, and `outer` that of the `]`.
+fn ownline(list: &SyntaxNode, index: usize, newline: &str, inner: &str, outer: &str) -> String {
+    let children: Vec<_> = list.children_with_tokens().collect();
+    let end = trailingcomment(&children, index);
+    let iswhitespace = |i: usize| children.get(i).is_some_and(|x| x.kind() == SyntaxKind::TOKEN_WHITESPACE);
+    let linebreak = |i: usize| iswhitespace(i) && children[i].to_string().contains('\n');
+
+    let mut out = String::new();
+    for (i, child) in children.iter().enumerate() {
+        if i == index && !index.checked_sub(1).is_some_and(linebreak) {
+            out.push_str(&format!("{newline}{inner}"));
+        }
+        if i == end && !linebreak(end) {
+            let next = if iswhitespace(end) { end + 1 } else { end };
+            let closing = children.get(next).is_some_and(|x| x.kind() == SyntaxKind::TOKEN_R_BRACK);
+            out.push_str(&format!("{newline}{}", if closing { outer } else { inner }));
+        }
+        // The spaces that shared the line with the element are replaced by the line breaks
+        let replaced = (i + 1 == index && !linebreak(i)) || (i == end && !linebreak(i));
+        if !(replaced && iswhitespace(i)) {
+            out.push_str(&child.to_string());
+        }
+    }
+    out
+}
+
+/// Turns elements disabled by disablearr back into list elements.
+pub(crate) fn enablearr(f: &str, query: &str, items: Vec<String>) -> Result<String, WriteError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(WriteError::ParseError),
+    };
+    let outnode = match findattr(&configbase, query) {
+        Some(x) => match enablearr_aux(&x, items) {
+            Some(x) => x,
+            None => return Err(WriteError::ArrayError),
+        },
+        None => return Err(WriteError::NoAttr),
+    };
+    Ok(outnode.to_string())
+}
+
+fn enablearr_aux(node: &SyntaxNode, items: Vec<String>) -> Option<SyntaxNode> {
+    let lists = getlists(node);
+    if lists.is_empty() {
+        return None;
+    }
+    let mut edits = vec![];
+    for list in lists {
+        let text = enablelist(&list.node, &items)?;
+        edits.push((list.node, text));
+    }
+    Some(replacelists(edits))
+}
+
+fn enablelist(child: &SyntaxNode, items: &[String]) -> Option<String> {
+    let newline = newline(child);
+    let mut replace = child.green().into_owned();
+    for (range, text) in disabledentries(child).into_iter().rev() {
+        if !items.contains(&disabledelement(&text)) {
+            continue;
+        }
+        let start = range.start;
+        for i in range.rev() {
+            replace = replace.remove_child(i);
+        }
+        let text = text.replace('\n', newline);
+        replace = replace.insert_child(
+            start,
+            rnix::NodeOrToken::Node(rnix::Root::parse(&text).syntax().green().into_owned()),
+        );
+    }
+    Some(SyntaxNode::new_root(replace).to_string())
+}
+
+pub(crate) fn setval(f: &str, query: &str, val: &str) -> Result<String, WriteError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(WriteError::ParseError),
+    };
+    let input = rnix::Root::parse(val);
+    if !input.errors().is_empty() {
+        return Err(WriteError::ValueError);
+    }
+    let input = match input.syntax().first_child() {
+        Some(x) => x,
+        None => return Err(WriteError::ValueError),
+    };
+    let outnode = match findattr(&configbase, query) {
+        Some(x) => match x.last_child() {
+            Some(value) => {
+                let replace = x
+                    .green()
+                    .replace_child(value.index(), rnix::NodeOrToken::Node(input.green().into_owned()));
+                x.replace_with(replace)
             }
-            let out = child.replace_with(replace);
+            None => return Err(WriteError::ParseError),
+        },
+        None => {
+            let (set, rest) = findparentset(&configbase, query);
+            return Ok(addvalue(&set, &rest, val).to_string());
+        }
+    };
+    Ok(rnix::Root::parse(&outnode.to_string()).syntax().to_string())
+}
 
-            let output = rnix::Root::parse(&out.to_string()).syntax();
-            return Some(output);
+pub(crate) fn rmval(f: &str, query: &str) -> Result<String, WriteError> {
+    let ast = rnix::Root::parse(f);
+    let configbase = match getcfgbase(&ast.syntax()) {
+        Some(x) => x,
+        None => return Err(WriteError::ParseError),
+    };
+    let node = match findattr(&configbase, query) {
+        Some(x) => x,
+        // The attribute may only be set through longer paths, like `programs.git.enable` for `programs.git`
+        None => {
+            let longer = findlonger(&configbase, query);
+            if longer.is_empty() {
+                return Err(WriteError::NoAttr);
+            }
+            let mut out = f.to_string();
+            for key in longer {
+                out = rmval(&out, &key)?;
+            }
+            return Ok(out);
+        }
+    };
+    let parent = match node.parent() {
+        Some(x) => x,
+        None => return Err(WriteError::ParseError),
+    };
+    let i = node.index();
+    let mut replace = parent.green().remove_child(i);
+    // Also remove the newline and indentation before the binding
+    if i > 0 {
+        if let Some(x) = replace.children().nth(i - 1).and_then(|y| y.as_token().cloned()) {
+            if x.text().trim().is_empty() && x.text().contains('\n') {
+                replace = replace.remove_child(i - 1);
+            }
         }
     }
-    None
+    let out = parent.replace_with(replace);
+    Ok(rnix::Root::parse(&out.to_string()).syntax().to_string())
+}
+
+/// Writes a string as a Nix string literal.
+pub(crate) fn quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gets the first list in the value of the attribute.
+    fn list(f: &str, query: &str) -> SyntaxNode {
+        let ast = rnix::Root::parse(f);
+        let configbase = getcfgbase(&ast.syntax()).unwrap();
+        let binding = findattr(&configbase, query).unwrap();
+        getlists(&binding).remove(0).node
+    }
+
+    #[test]
+    fn indentunit_uses_tabs() {
+        assert_eq!(indentunit("{\n\thome.packages = [\n\t\tpkgs.git\n\t];\n}\n"), "\t");
+    }
+
+    #[test]
+    fn indentunit_uses_smallest_indentation() {
+        assert_eq!(indentunit("{\n    home.packages = [\n        pkgs.git\n    ];\n}\n"), "    ");
+        assert_eq!(indentunit("{ home.packages = [ ]; }"), "  ");
+    }
+
+    #[test]
+    fn newline_follows_file() {
+        let f = "{\r\n  home.packages = [\r\n    pkgs.git\r\n  ];\r\n}\r\n";
+        assert_eq!(newline(&list(f, "home.packages")), "\r\n");
+        let f = "{\n  home.packages = [\n    pkgs.git\n  ];\n}\n";
+        assert_eq!(newline(&list(f, "home.packages")), "\n");
+    }
+
+    #[test]
+    fn childindent_follows_children() {
+        let f = "{\n  home.packages = [\n      pkgs.git\n  ];\n}\n";
+        assert_eq!(childindent(&list(f, "home.packages")), "      ");
+    }
+
+    #[test]
+    fn childindent_of_empty_nested_list() {
+        let f = "{\n  home = {\n    packages = [ ];\n  };\n}\n";
+        assert_eq!(childindent(&list(f, "home.packages")), "      ");
+    }
+
+    #[test]
+    fn addtoarr_tabs() {
+        let f = "{\n\thome.packages = [\n\t\tpkgs.git\n\t];\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 100).unwrap(),
+            "{\n\thome.packages = [\n\t\tpkgs.git\n\t\tpkgs.hello\n\t];\n}\n"
+        );
+    }
+
+    #[test]
+    fn addtoarr_crlf() {
+        let f = "{\r\n  home.packages = [\r\n    pkgs.git\r\n  ];\r\n}\r\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 100).unwrap(),
+            "{\r\n  home.packages = [\r\n    pkgs.git\r\n    pkgs.hello\r\n  ];\r\n}\r\n"
+        );
+    }
+
+    #[test]
+    fn addtoarr_nested_set() {
+        let f = "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n  };\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 100).unwrap(),
+            "{\n  home = {\n    packages = [\n      pkgs.git\n      pkgs.hello\n    ];\n  };\n}\n"
+        );
+    }
+
+    #[test]
+    fn addtoarr_single_line() {
+        let f = "{\n  home.packages = [ pkgs.git ];\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 100).unwrap(),
+            "{\n  home.packages = [ pkgs.git pkgs.hello ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn addtoarr_single_line_several() {
+        let f = "{\n  home.packages = [];\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.a".to_string(), "pkgs.b".to_string()], false, 100).unwrap(),
+            "{\n  home.packages = [ pkgs.a pkgs.b ];\n}\n"
+        );
+        let f = "{\n  home.packages = [pkgs.git];\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.a".to_string(), "pkgs.b".to_string()], false, 100).unwrap(),
+            "{\n  home.packages = [pkgs.git pkgs.a pkgs.b];\n}\n"
+        );
+    }
+
+    #[test]
+    fn addtoarr_single_line_sorted() {
+        let f = "{\n  home.packages = [ pkgs.c ];\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.d".to_string(), "pkgs.a".to_string(), "pkgs.b".to_string()], true, 100).unwrap(),
+            "{\n  home.packages = [ pkgs.a pkgs.b pkgs.c pkgs.d ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn addtoarr_single_line_too_wide() {
+        let f = "{\n  home.packages = [ pkgs.git ];\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 20).unwrap(),
+            "{\n  home.packages = [\n    pkgs.git\n    pkgs.hello\n  ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn addtoarr_bracket_after_last_element() {
+        let f = "{\n  home.packages = [\n    pkgs.git];\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 100).unwrap(),
+            "{\n  home.packages = [\n    pkgs.git\n    pkgs.hello];\n}\n"
+        );
+    }
+
+    #[test]
+    fn addtocond_adds_under_condition() {
+        let f = "{\n  home.packages = [\n    pkgs.git\n  ] ++ lib.optionals stdenv.isLinux [\n    pkgs.gdb\n  ];\n}\n";
+        assert_eq!(
+            addtocond(f, "home.packages", vec!["pkgs.strace".to_string()], "stdenv.isLinux", false, 100).unwrap(),
+            "{\n  home.packages = [\n    pkgs.git\n  ] ++ lib.optionals stdenv.isLinux [\n    pkgs.gdb\n    pkgs.strace\n  ];\n}\n"
+        );
+        assert!(matches!(
+            addtocond(f, "home.packages", vec!["pkgs.strace".to_string()], "stdenv.isDarwin", false, 100),
+            Err(WriteError::NoConditionalArray(_))
+        ));
+    }
+
+    #[test]
+    fn addtoarr_creates_list_with_tabs() {
+        let f = "{\n\thome.username = \"jane\";\n}\n";
+        assert_eq!(
+            addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 100).unwrap(),
+            "{\n\thome.username = \"jane\";\n\thome.packages = [\n\t\tpkgs.hello\n\t];\n}\n"
+        );
+    }
+
+    /// Removes the items from `list`, written as the value of home.packages, and gives the list that's left.
+    fn rm(list: &str, items: &[&str]) -> String {
+        let f = format!("{{\n  home.packages = {list};\n}}\n");
+        let items = items.iter().map(|x| x.to_string()).collect();
+        let out = rmarr(&f, "home.packages", items).unwrap();
+        out.strip_prefix("{\n  home.packages = ").unwrap().strip_suffix(";\n}\n").unwrap().to_string()
+    }
+
+    #[test]
+    fn rmarr_trailing_comment() {
+        assert_eq!(rm("[\n    a # the first\n    b\n  ]", &["a"]), "[\n    b\n  ]");
+    }
+
+    #[test]
+    fn rmarr_keeps_heading_comment() {
+        assert_eq!(rm("[\n    # tools\n    a\n    b\n  ]", &["a"]), "[\n    # tools\n    b\n  ]");
+    }
+
+    #[test]
+    fn rmarr_comment_above() {
+        assert_eq!(rm("[\n    a\n    # about b\n    # more about b\n    b\n  ]", &["b"]), "[\n    a\n  ]");
+    }
+
+    #[test]
+    fn rmarr_keeps_comment_before_blank_line() {
+        assert_eq!(rm("[\n    a\n    # note\n\n    b\n  ]", &["b"]), "[\n    a\n    # note\n  ]");
+    }
+
+    #[test]
+    fn rmarr_first_and_last() {
+        assert_eq!(rm("[\n    a\n    b\n  ]", &["a"]), "[\n    b\n  ]");
+        assert_eq!(rm("[\n    a\n    b\n  ]", &["b"]), "[\n    a\n  ]");
+        assert_eq!(rm("[a b]", &["a"]), "[b]");
+        assert_eq!(rm("[a b]", &["b"]), "[a]");
+        assert_eq!(rm("[ a ]", &["a"]), "[ ]");
+    }
+
+    #[test]
+    fn rmarr_neighbours() {
+        assert_eq!(rm("[pkgs.a pkgs.b]", &["pkgs.a", "pkgs.b"]), "[]");
+        assert_eq!(rm("[a b c]", &["a", "b"]), "[c]");
+        assert_eq!(rm("[a b c]", &["b", "c"]), "[a]");
+        assert_eq!(rm("[a b c]", &["a", "c"]), "[b]");
+        assert_eq!(rm("[ a b c ]", &["a", "b", "c"]), "[ ]");
+        assert_eq!(rm("[\n    a\n    b\n    c\n  ]", &["a", "b"]), "[\n    c\n  ]");
+    }
+
+    /// Disables the items in `list`, written as the value of home.packages, and gives the list that's left.
+    fn disable(list: &str, items: &[&str]) -> String {
+        let f = format!("{{\n  home.packages = {list};\n}}\n");
+        let items = items.iter().map(|x| x.to_string()).collect();
+        let out = disablearr(&f, "home.packages", items).unwrap();
+        out.strip_prefix("{\n  home.packages = ").unwrap().strip_suffix(";\n}\n").unwrap().to_string()
+    }
+
+    /// Enables the items in `list` again, like `disable`.
+    fn enable(list: &str, items: &[&str]) -> String {
+        let f = format!("{{\n  home.packages = {list};\n}}\n");
+        let items = items.iter().map(|x| x.to_string()).collect();
+        let out = enablearr(&f, "home.packages", items).unwrap();
+        out.strip_prefix("{\n  home.packages = ").unwrap().strip_suffix(";\n}\n").unwrap().to_string()
+    }
+
+    /// Gets the disabled entries of `value`, as disabledentries finds them.
+    fn disabled(value: &str) -> Vec<String> {
+        let f = format!("{{\n  home.packages = {value};\n}}\n");
+        crate::nix_read::disabledentries(&list(&f, "home.packages")).into_iter().map(|(_, text)| text).collect()
+    }
+
+    #[test]
+    fn disablearr_roundtrip() {
+        let f = "[\n    pkgs.a\n    pkgs.b\n  ]";
+        let out = disable(f, &["pkgs.a"]);
+        assert_eq!(out, "[\n    # hdn-disabled: pkgs.a\n    pkgs.b\n  ]");
+        assert_eq!(disabled(&out), vec!["pkgs.a"]);
+        assert_eq!(enable(&out, &["pkgs.a"]), f);
+    }
+
+    #[test]
+    fn disablearr_trailing_comment() {
+        let f = "[\n    pkgs.a # the editor\n    pkgs.b\n  ]";
+        let out = disable(f, &["pkgs.a"]);
+        assert_eq!(out, "[\n    # hdn-disabled: pkgs.a # the editor\n    pkgs.b\n  ]");
+        assert_eq!(disabled(&out), vec!["pkgs.a # the editor"]);
+        assert_eq!(crate::nix_read::disabledelement("pkgs.a # the editor"), "pkgs.a");
+        assert_eq!(enable(&out, &["pkgs.a"]), f);
+    }
+
+    #[test]
+    fn disablearr_multiline_element() {
+        let elem = "(pkgs.foo.override {\n      x = 1;\n    })";
+        let f = format!("[\n    {elem}\n    pkgs.b\n  ]");
+        let out = disable(&f, &[elem]);
+        assert_eq!(
+            out,
+            "[\n    # hdn-disabled: (pkgs.foo.override {\n    # hdn-disabled|   x = 1;\n    # hdn-disabled| })\n    pkgs.b\n  ]"
+        );
+        assert_eq!(disabled(&out), vec![elem]);
+        assert_eq!(crate::nix_read::disabledelement(elem), elem);
+        assert_eq!(enable(&out, &[elem]), f);
+    }
+
+    #[test]
+    fn disablearr_single_line() {
+        let out = disable("[ pkgs.a pkgs.b ]", &["pkgs.a"]);
+        assert_eq!(out, "[\n    # hdn-disabled: pkgs.a\n    pkgs.b\n  ]");
+        assert_eq!(enable(&out, &["pkgs.a"]), "[\n    pkgs.a\n    pkgs.b\n  ]");
+    }
+
+    #[test]
+    fn disablearr_neighbours_on_line() {
+        let f = "[\n    pkgs.a pkgs.b\n    pkgs.c\n  ]";
+        assert_eq!(disable(f, &["pkgs.a"]), "[\n    # hdn-disabled: pkgs.a\n    pkgs.b\n    pkgs.c\n  ]");
+        assert_eq!(disable(f, &["pkgs.b"]), "[\n    pkgs.a\n    # hdn-disabled: pkgs.b\n    pkgs.c\n  ]");
+        assert_eq!(
+            disable(f, &["pkgs.a", "pkgs.b"]),
+            "[\n    # hdn-disabled: pkgs.a\n    # hdn-disabled: pkgs.b\n    pkgs.c\n  ]"
+        );
+    }
+
+    #[test]
+    fn disablearr_bracket_on_line() {
+        let f = "[ (pkgs.foo.override {\n    x = 1;\n  }) pkgs.bar ]";
+        let out = disable(f, &["pkgs.bar"]);
+        assert_eq!(out, "[ (pkgs.foo.override {\n    x = 1;\n  })\n    # hdn-disabled: pkgs.bar\n  ]");
+        assert_eq!(disabled(&out), vec!["pkgs.bar"]);
+        assert_eq!(disable("[\n    pkgs.a\n    pkgs.b]", &["pkgs.b"]), "[\n    pkgs.a\n    # hdn-disabled: pkgs.b\n  ]");
+    }
+
+    #[test]
+    fn addvalue_tabs() {
+        let f = "{\n\thome.username = \"jane\";\n}\n";
+        assert_eq!(
+            setval(f, "programs.git.enable", "true").unwrap(),
+            "{\n\thome.username = \"jane\";\n\tprograms.git.enable = true;\n}\n"
+        );
+    }
+
+    #[test]
+    fn addvalue_crlf() {
+        let f = "{\r\n  home.username = \"jane\";\r\n}\r\n";
+        assert_eq!(
+            setval(f, "programs.git.enable", "true").unwrap(),
+            "{\r\n  home.username = \"jane\";\r\n  programs.git.enable = true;\r\n}\r\n"
+        );
+    }
+
+    #[test]
+    fn addvalue_nested_set() {
+        let f = "{\n  programs = {\n    fish.enable = true;\n  };\n}\n";
+        assert_eq!(
+            setval(f, "programs.git.enable", "true").unwrap(),
+            "{\n  programs = {\n    fish.enable = true;\n    git.enable = true;\n  };\n}\n"
+        );
+    }
+
+    const DOTTED: &str = "{\n  home.packages = [\n    pkgs.git\n  ];\n  home.username = \"jane\";\n}\n";
+    const NESTED: &str = "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n    username = \"jane\";\n  };\n}\n";
+    const MIXED: &str = "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n  };\n  home.username = \"jane\";\n}\n";
+
+    /// Adds a package and removes it again, checking the list in between and that the file comes back unchanged.
+    fn roundtrip_list(f: &str, added: &str) {
+        assert_eq!(crate::nix_read::getarrvals(f, "home.packages").unwrap(), vec!["pkgs.git"]);
+        let out = addtoarr(f, "home.packages", vec!["pkgs.hello".to_string()], false, 100).unwrap();
+        assert_eq!(out, added);
+        assert_eq!(crate::nix_read::getarrvals(&out, "home.packages").unwrap(), vec!["pkgs.git", "pkgs.hello"]);
+        assert_eq!(rmarr(&out, "home.packages", vec!["pkgs.hello".to_string()]).unwrap(), f);
+    }
+
+    /// Sets a new value and removes it again, checking that the file comes back unchanged.
+    fn roundtrip_value(f: &str, set: &str) {
+        let out = setval(f, "home.stateVersion", "\"24.05\"").unwrap();
+        assert_eq!(out, set);
+        assert_eq!(rmval(&out, "home.stateVersion").unwrap(), f);
+    }
+
+    #[test]
+    fn roundtrip_dotted() {
+        roundtrip_list(DOTTED, "{\n  home.packages = [\n    pkgs.git\n    pkgs.hello\n  ];\n  home.username = \"jane\";\n}\n");
+        roundtrip_value(DOTTED, "{\n  home.packages = [\n    pkgs.git\n  ];\n  home.stateVersion = \"24.05\";\n  home.username = \"jane\";\n}\n");
+        assert_eq!(
+            setval(DOTTED, "home.username", "\"joe\"").unwrap(),
+            "{\n  home.packages = [\n    pkgs.git\n  ];\n  home.username = \"joe\";\n}\n"
+        );
+        assert_eq!(rmval(DOTTED, "home.username").unwrap(), "{\n  home.packages = [\n    pkgs.git\n  ];\n}\n");
+    }
+
+    #[test]
+    fn roundtrip_nested() {
+        roundtrip_list(NESTED, "{\n  home = {\n    packages = [\n      pkgs.git\n      pkgs.hello\n    ];\n    username = \"jane\";\n  };\n}\n");
+        roundtrip_value(NESTED, "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n    username = \"jane\";\n    stateVersion = \"24.05\";\n  };\n}\n");
+        assert_eq!(
+            setval(NESTED, "home.username", "\"joe\"").unwrap(),
+            "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n    username = \"joe\";\n  };\n}\n"
+        );
+        assert_eq!(rmval(NESTED, "home.username").unwrap(), "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n  };\n}\n");
+    }
+
+    #[test]
+    fn roundtrip_mixed() {
+        roundtrip_list(MIXED, "{\n  home = {\n    packages = [\n      pkgs.git\n      pkgs.hello\n    ];\n  };\n  home.username = \"jane\";\n}\n");
+        roundtrip_value(MIXED, "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n    stateVersion = \"24.05\";\n  };\n  home.username = \"jane\";\n}\n");
+        assert_eq!(
+            setval(MIXED, "home.username", "\"joe\"").unwrap(),
+            "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n  };\n  home.username = \"joe\";\n}\n"
+        );
+        assert_eq!(rmval(MIXED, "home.username").unwrap(), "{\n  home = {\n    packages = [\n      pkgs.git\n    ];\n  };\n}\n");
+    }
 }
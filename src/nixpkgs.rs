@@ -0,0 +1,338 @@
+use std::{fs, io};
+use std::collections::HashMap;
+use std::env::VarError;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::flake::Flake;
+use crate::xdg;
+
+const DEFAULT_INDEX_COMMAND: [&str; 6] = ["nix-env", "-f", "<nixpkgs>", "-qaP", "--json", "--meta"];
+
+#[derive(Error, Debug)]
+pub(crate) enum EvalError {
+    #[error("could not run {0}")]
+    CouldNotRun(String, #[source] io::Error),
+    #[error("{0} returned a non-zero exit code: {1}")]
+    Unsuccessful(String, String),
+    #[error("{0} printed {1:?}, instead of true or false")]
+    UnexpectedOutput(String, String),
+}
+
+/// Checks whether attributes exist in the nixpkgs that home-manager builds with.
+///
+/// The command is run once per attribute, with `{attr}` in its arguments replaced by the attribute path
+/// (and `{flake}` by the flake's home configuration, for flake setups). It should print `true` or `false`.
+pub(crate) struct Evaluator {
+    command: Vec<String>,
+}
+
+impl Evaluator {
+    pub(crate) fn new(config: &Config, flake: Option<&Flake>) -> Evaluator {
+        let command = match (&config.evaluator, flake) {
+            (Some(command), _) => command.clone(),
+            (None, Some(_)) => vec![
+                "nix".to_string(),
+                "eval".to_string(),
+                "{flake}.config._module.args.pkgs".to_string(),
+                "--apply".to_string(),
+                "pkgs: pkgs ? {attr}".to_string(),
+            ],
+            (None, None) => vec![
+                "nix-instantiate".to_string(),
+                "--eval".to_string(),
+                "--expr".to_string(),
+                "import <nixpkgs> {} ? {attr}".to_string(),
+            ],
+        };
+
+        let flake = flake
+            .map(|flake| format!("{}#homeConfigurations.\"{}\"", flake.dir.display(), flake.name))
+            .unwrap_or_default();
+        let command = command.iter()
+            .map(|arg| arg.replace("{flake}", &flake))
+            .collect();
+
+        Evaluator { command }
+    }
+
+    pub(crate) fn exists(&self, attr: &str) -> Result<bool, EvalError> {
+        use crate::nixpkgs::EvalError::*;
+
+        let command: Vec<String> = self.command.iter()
+            .map(|arg| arg.replace("{attr}", attr))
+            .collect();
+        let display = command.join(" ");
+        let Some((program, args)) = command.split_first() else {
+            return Err(UnexpectedOutput(display, String::new()));
+        };
+
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .map_err(|error| CouldNotRun(display.clone(), error))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(Unsuccessful(display, stderr));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        match stdout.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(UnexpectedOutput(display, stdout))
+        }
+    }
+}
+
+/// Gets the nixpkgs attribute path of a list element, like `hello` for `pkgs.hello`.
+/// Returns `None` for anything that isn't a plain attribute path, like `(pkgs.foo.override { })`.
+pub(crate) fn attrpath(package: &str) -> Option<&str> {
+    let attrpath = package.strip_prefix("pkgs.").unwrap_or(package);
+    let is_attrpath = !attrpath.is_empty()
+        && attrpath.split('.').all(|part| {
+            part.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''))
+        });
+    is_attrpath.then_some(attrpath)
+}
+
+/// Gets the innermost `with` namespace that is nixpkgs or an attribute of it,
+/// as an attribute path relative to nixpkgs: `""` for `with pkgs;`, `"python3Packages"` for `with pkgs.python3Packages;`.
+fn pkgs_scope(scopes: &[String]) -> Option<&str> {
+    scopes.iter().rev().find_map(|scope| match scope.as_str() {
+        "pkgs" => Some(""),
+        scope => scope.strip_prefix("pkgs."),
+    })
+}
+
+/// Gets what a list element refers to, as an attribute path relative to nixpkgs,
+/// so that `hello` inside `with pkgs;` and `pkgs.hello` are the same package.
+///
+/// `scopes` are the `with` namespaces around the list, as given by nix_read::getwithscopes.
+/// Packages given on the command line are read the same way, so `hdn add vim-fugitive`
+/// on a `with pkgs.vimPlugins;` list means `vimPlugins.vim-fugitive`.
+/// Elements that aren't plain attribute paths are compared as written.
+pub(crate) fn identity(element: &str, scopes: &[String]) -> String {
+    let Some(path) = attrpath(element) else {
+        return element.to_string();
+    };
+    match pkgs_scope(scopes) {
+        Some(scope) if !scope.is_empty() && !element.starts_with("pkgs.") => format!("{scope}.{path}"),
+        _ => path.to_string()
+    }
+}
+
+/// Writes a package, given by its identity, the way the list refers to packages:
+/// relative to the `with` namespace inside `with pkgs;`, and as `pkgs.<attr>` otherwise.
+pub(crate) fn qualify(identity: &str, scopes: &[String]) -> String {
+    let Some(path) = attrpath(identity) else {
+        return identity.to_string();
+    };
+    match pkgs_scope(scopes) {
+        Some("") => path.to_string(),
+        Some(scope) => match path.strip_prefix(scope).and_then(|rest| rest.strip_prefix('.')) {
+            Some(rest) => rest.to_string(),
+            None => format!("pkgs.{path}")
+        },
+        None => format!("pkgs.{path}")
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum IndexError {
+    #[error("could not get $HOME environment variable")]
+    NoHomeEnvironmentVariable(#[source] VarError),
+    #[error("could not run {0} to build the package index")]
+    CouldNotRun(String, #[source] io::Error),
+    #[error("{0} returned a non-zero exit code")]
+    Unsuccessful(String),
+    #[error("could not read {0}")]
+    CouldNotRead(PathBuf, #[source] io::Error),
+    #[error("could not parse {0}")]
+    CouldNotParse(String, #[source] serde_json::Error),
+    #[error("could not write {0}")]
+    CouldNotWrite(PathBuf, #[source] io::Error),
+}
+
+/// A package in the cached index of nixpkgs
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IndexedPackage {
+    pub(crate) attr: String,
+    pub(crate) version: String,
+    pub(crate) description: String,
+}
+
+/// The parts of `nix-env -qaP --json --meta` output that the index keeps
+#[derive(Deserialize)]
+struct NixEnvPackage {
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    meta: NixEnvMeta,
+}
+
+#[derive(Deserialize, Default)]
+struct NixEnvMeta {
+    #[serde(default)]
+    description: Option<String>,
+}
+
+/// Gets the index of nixpkgs, cached in $XDG_CACHE_HOME/hdn/index.json.
+///
+/// The index is built on first use from `nix-env -qaP --json --meta` (or the configured index-command),
+/// or from `from`, a file with the same JSON. Pass `refresh` to build it again.
+pub(crate) fn getindex(config: &Config, refresh: bool, from: Option<&Path>) -> Result<Vec<IndexedPackage>, IndexError> {
+    use crate::nixpkgs::IndexError::*;
+
+    let cache = xdg::cache_home()
+        .map_err(NoHomeEnvironmentVariable)?
+        .join("hdn/index.json");
+
+    if !refresh && from.is_none() && cache.is_file() {
+        let content = fs::read_to_string(&cache)
+            .map_err(|error| CouldNotRead(cache.clone(), error))?;
+        return serde_json::from_str(&content)
+            .map_err(|error| CouldNotParse(cache.display().to_string(), error));
+    }
+
+    let (source, content) = match from {
+        Some(from) => {
+            let content = fs::read_to_string(from)
+                .map_err(|error| CouldNotRead(from.to_path_buf(), error))?;
+            (from.display().to_string(), content)
+        }
+        None => run_index_command(config)?
+    };
+    let packages: HashMap<String, NixEnvPackage> = serde_json::from_str(&content)
+        .map_err(|error| CouldNotParse(source, error))?;
+
+    let mut index: Vec<IndexedPackage> = packages.into_iter()
+        .map(|(attr, package)| IndexedPackage {
+            // Without -f, nix-env prefixes attributes with the channel name
+            attr: attr.strip_prefix("nixpkgs.").map(str::to_string).unwrap_or(attr),
+            version: package.version,
+            description: package.meta.description.unwrap_or_default(),
+        })
+        .collect();
+    index.sort_by(|a, b| a.attr.cmp(&b.attr));
+
+    if let Some(dir) = cache.parent() {
+        fs::create_dir_all(dir).map_err(|error| CouldNotWrite(cache.clone(), error))?;
+    }
+    let content = serde_json::to_string(&index)
+        .map_err(|error| CouldNotParse(cache.display().to_string(), error))?;
+    fs::write(&cache, content).map_err(|error| CouldNotWrite(cache, error))?;
+
+    Ok(index)
+}
+
+fn run_index_command(config: &Config) -> Result<(String, String), IndexError> {
+    use crate::nixpkgs::IndexError::*;
+
+    let command: Vec<String> = match &config.index_command {
+        Some(command) => command.clone(),
+        None => DEFAULT_INDEX_COMMAND.iter().map(|s| s.to_string()).collect()
+    };
+    let display = command.join(" ");
+    let Some((program, args)) = command.split_first() else {
+        return Err(Unsuccessful(display));
+    };
+
+    eprintln!("Building the package index with {display}; this may take a while...");
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|error| CouldNotRun(display.clone(), error))?;
+    if !output.status.success() {
+        return Err(Unsuccessful(display));
+    }
+
+    Ok((display, String::from_utf8_lossy(&output.stdout).to_string()))
+}
+
+/// Ranks the packages in the index against `query` with fuzzy matching.
+///
+/// Matches on the attribute path count for more than matches on the description,
+/// and an exact attribute name comes first.
+pub(crate) fn search<'a>(index: &'a [IndexedPackage], query: &str) -> Vec<&'a IndexedPackage> {
+    let matcher = SkimMatcherV2::default().ignore_case();
+
+    let mut results: Vec<(i64, &IndexedPackage)> = index.iter()
+        .filter_map(|package| {
+            let name = package.attr.rsplit('.').next().unwrap_or(&package.attr);
+            let exact = if name.eq_ignore_ascii_case(query) { 1_000_000 } else { 0 };
+            let attr_score = matcher.fuzzy_match(&package.attr, query).map(|score| score * 2);
+            let description_score = if package.description.to_lowercase().contains(&query.to_lowercase()) {
+                matcher.fuzzy_match(&package.description, query)
+            } else {
+                None
+            };
+            let score = attr_score.max(description_score)?;
+            Some((score + exact, package))
+        })
+        .collect();
+
+    // Break ties in favor of shorter attribute paths, which tend to be the main package
+    results.sort_by(|(a_score, a), (b_score, b)| {
+        b_score.cmp(a_score).then(a.attr.len().cmp(&b.attr.len()))
+    });
+    results.into_iter().map(|(_, package)| package).collect()
+}
+
+/// Suggests the names closest to `attr`, for when `attr` doesn't exist.
+///
+/// A name is close if it is `attr` with a suffix added or dropped, like `nodejs_20` for `nodejs`,
+/// or if it is within a few edits of `attr`. Renames come first, then names by edit distance.
+pub(crate) fn suggest<'a>(attr: &str, names: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let max_distance = (attr.chars().count() / 3).clamp(1, 3);
+
+    let mut suggestions: Vec<(usize, &str)> = names.into_iter()
+        .filter(|name| *name != attr)
+        .filter_map(|name| {
+            if is_rename(attr, name) || is_rename(name, attr) {
+                return Some((0, name));
+            }
+            // Edit distance is at least the difference in length, so skip computing it when that is too big
+            if name.chars().count().abs_diff(attr.chars().count()) > max_distance {
+                return None;
+            }
+            let distance = edit_distance(&attr.to_lowercase(), &name.to_lowercase());
+            (distance <= max_distance).then_some((distance, name))
+        })
+        .collect();
+
+    // Among renames of the same length, put the highest version first
+    suggestions.sort_by(|(a_distance, a), (b_distance, b)| {
+        let by_name = if *a_distance == 0 { b.cmp(a) } else { a.cmp(b) };
+        a_distance.cmp(b_distance).then(a.len().cmp(&b.len())).then(by_name)
+    });
+    suggestions.into_iter().map(|(_, name)| name).take(5).collect()
+}
+
+/// Whether `name` is `base` with a suffix, like `nodejs_20` or `python3`.
+fn is_rename(base: &str, name: &str) -> bool {
+    match name.strip_prefix(base) {
+        Some(suffix) => suffix.starts_with(|c: char| c == '_' || c == '-' || c.is_ascii_digit()),
+        None => false
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
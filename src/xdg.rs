@@ -0,0 +1,23 @@
+use std::env;
+use std::env::VarError;
+use std::path::PathBuf;
+
+/// Gets an XDG base directory from `var`, falling back on `default` relative to $HOME.
+fn xdg_home(var: &str, default: &str) -> Result<PathBuf, VarError> {
+    match env::var(var) {
+        Ok(s) if !s.is_empty() => Ok(PathBuf::from(s)),
+        _ => Ok(PathBuf::from(env::var("HOME")?).join(default))
+    }
+}
+
+pub(crate) fn config_home() -> Result<PathBuf, VarError> {
+    xdg_home("XDG_CONFIG_HOME", ".config")
+}
+
+pub(crate) fn cache_home() -> Result<PathBuf, VarError> {
+    xdg_home("XDG_CACHE_HOME", ".cache")
+}
+
+pub(crate) fn state_home() -> Result<PathBuf, VarError> {
+    xdg_home("XDG_STATE_HOME", ".local/state")
+}